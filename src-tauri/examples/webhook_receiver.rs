@@ -0,0 +1,63 @@
+//! A local stand-in for a webhook endpoint. Prints every delivery and whether
+//! its signature checks out.
+//!
+//!     cargo run --example webhook_receiver -- [--port 8787] [--fail 2] [--data-dir PATH | --secret-file PATH]
+//!
+//! Then add `http://127.0.0.1:8787/` as a webhook URL. `--fail N` answers the
+//! first N requests with a 500 to watch the retries. The secret is the app's,
+//! found the way the app finds its data directory, unless `--secret-file` says
+//! otherwise.
+
+use std::path::PathBuf;
+
+use app_lib::{paths, webhooks};
+use tiny_http::{Response, Server};
+
+fn flag(args: &[String], name: &str) -> Option<String> {
+    args.iter().position(|a| a == name).and_then(|i| args.get(i + 1)).cloned()
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let port: u16 = flag(&args, "--port").and_then(|p| p.parse().ok()).unwrap_or(8787);
+    let mut fail: u32 = flag(&args, "--fail").and_then(|n| n.parse().ok()).unwrap_or(0);
+    let secret_file = flag(&args, "--secret-file")
+        .map(PathBuf::from)
+        .unwrap_or_else(webhooks::secret_path);
+    let secret = paths::secret(&secret_file).expect("Could not read the webhook secret");
+
+    let server = Server::http(("127.0.0.1", port)).expect("Could not listen");
+    println!("Listening on http://127.0.0.1:{}/", port);
+
+    for mut request in server.incoming_requests() {
+        let header = |name: &'static str| {
+            request
+                .headers()
+                .iter()
+                .find(|h| h.field.equiv(name))
+                .map(|h| h.value.to_string())
+                .unwrap_or_default()
+        };
+        let event = header("X-Stretchia-Event");
+        let delivery = header("X-Stretchia-Delivery");
+        let signature = header("X-Stretchia-Signature");
+
+        let mut body = String::new();
+        let _ = request.as_reader().read_to_string(&mut body);
+
+        let verdict = if signature == webhooks::sign(&secret, &body) {
+            "signature ok"
+        } else {
+            "SIGNATURE MISMATCH"
+        };
+        println!("#{} {} ({})\n{}\n", delivery, event, verdict, body);
+
+        let status = if fail > 0 {
+            fail -= 1;
+            500
+        } else {
+            200
+        };
+        let _ = request.respond(Response::empty(status));
+    }
+}
@@ -0,0 +1,146 @@
+use rusqlite::Connection;
+
+use crate::db;
+use crate::db_worker::DbWorker;
+use crate::error::{self, Error};
+use crate::events::{self, Event};
+use crate::timer::{Mode, TimerSnapshot};
+use crate::{lock, AppState, SharedState};
+
+/// How long after a timer action it can still be undone.
+pub const UNDO_GRACE_S: i64 = 120;
+const UNDO_STACK_SIZE: usize = 5;
+
+pub struct UndoEntry {
+    at: i64,
+    workout_id: Option<i64>,
+    before: TimerSnapshot,
+}
+
+fn push_undo(s: &mut AppState, workout_id: Option<i64>, before: TimerSnapshot) {
+    s.undo_stack.push(UndoEntry {
+        at: chrono::Utc::now().timestamp(),
+        workout_id,
+        before,
+    });
+    if s.undo_stack.len() > UNDO_STACK_SIZE {
+        s.undo_stack.remove(0);
+    }
+}
+
+pub fn can_undo(s: &AppState) -> bool {
+    let now = chrono::Utc::now().timestamp();
+    s.undo_stack.last().is_some_and(|u| now - u.at <= UNDO_GRACE_S)
+}
+
+// Actions that record a workout change the timer under its lock first, so two
+// of them (say the window and MQTT) can't both claim the same stretch or walk.
+// The row is then written on the database worker without holding the lock;
+// if that fails, the timer goes back to where it was.
+
+/// Writes the workout `insert` records for an action already applied to the
+/// timer, which was `before` until then.
+async fn record<F>(worker: &DbWorker, state: &SharedState, before: TimerSnapshot, insert: F) -> error::Result<()>
+where
+    F: FnOnce(&Connection) -> rusqlite::Result<i64> + Send + 'static,
+{
+    let result = worker
+        .call(move |conn| -> error::Result<i64> {
+            let id = insert(conn)?;
+            events::emit(conn, Event::WorkoutRecorded(db::get_workout(conn, id)?));
+            Ok(id)
+        })
+        .await;
+    let mut s = lock(state);
+    match result {
+        Ok(id) => {
+            push_undo(&mut s, Some(id), before);
+            Ok(())
+        }
+        Err(e) => {
+            s.timer.restore(before);
+            Err(e)
+        }
+    }
+}
+
+/// Resets the timer for a break, returning it as it was.
+fn claim_break(state: &SharedState) -> TimerSnapshot {
+    let mut s = lock(state);
+    let before = s.timer.snapshot();
+    s.timer.reset();
+    before
+}
+
+pub async fn stretch(worker: &DbWorker, state: &SharedState) -> error::Result<()> {
+    let before = claim_break(state);
+    let sitting_before_s = before.elapsed_s as i64;
+    record(worker, state, before, move |conn| db::record_stretch(conn, sitting_before_s)).await
+}
+
+pub async fn skip(worker: &DbWorker, state: &SharedState) -> error::Result<()> {
+    let before = claim_break(state);
+    let sitting_before_s = before.elapsed_s as i64;
+    record(worker, state, before, move |conn| db::record_skip(conn, sitting_before_s)).await
+}
+
+pub fn start_treadmill(worker: &DbWorker, state: &SharedState) {
+    let mut s = lock(state);
+    let before = s.timer.snapshot();
+    s.timer.start_treadmill();
+    push_undo(&mut s, None, before);
+    let sitting_before_s = s.timer.sitting_before_s;
+    worker.execute(move |conn| -> error::Result<()> {
+        events::emit(conn, Event::TreadmillStarted { sitting_before_s });
+        Ok(())
+    });
+}
+
+pub async fn stop_treadmill(worker: &DbWorker, state: &SharedState) -> error::Result<()> {
+    let (before, (started, duration, sitting_before)) = {
+        let mut s = lock(state);
+        if s.timer.mode != Mode::Treadmill {
+            return Err(Error::conflict("Treadmill is not running"));
+        }
+        (s.timer.snapshot(), s.timer.stop_treadmill())
+    };
+    record(worker, state, before, move |conn| {
+        db::record_treadmill(conn, started, duration as i64, sitting_before as i64)
+    })
+    .await
+}
+
+/// Longest snooze accepted, in minutes.
+pub const MAX_SNOOZE_MIN: u64 = 120;
+
+/// Holds reminders back for `minutes`.
+pub fn snooze(state: &SharedState, minutes: u64) -> error::Result<()> {
+    if !(1..=MAX_SNOOZE_MIN).contains(&minutes) {
+        return Err(Error::invalid(format!("Snooze must be between 1 and {} minutes", MAX_SNOOZE_MIN)));
+    }
+    lock(state).timer.snooze(minutes);
+    Ok(())
+}
+
+/// Reverts the latest timer action if it is still within the grace window:
+/// the workout it recorded is removed and the timer goes back to where it was.
+/// Returns false when there is nothing left to undo.
+pub async fn undo(worker: &DbWorker, state: &SharedState) -> error::Result<bool> {
+    let entry = {
+        let mut s = lock(state);
+        let now = chrono::Utc::now().timestamp();
+        s.undo_stack.retain(|u| now - u.at <= UNDO_GRACE_S);
+        s.undo_stack.pop()
+    };
+    let Some(entry) = entry else {
+        return Ok(false);
+    };
+    if let Some(id) = entry.workout_id {
+        if let Err(e) = worker.call(move |conn| db::remove_workout(conn, id)).await {
+            lock(state).undo_stack.push(entry);
+            return Err(e);
+        }
+    }
+    lock(state).timer.restore(entry.before);
+    Ok(true)
+}
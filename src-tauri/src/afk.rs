@@ -1,3 +1,21 @@
+/// Why the user is considered away from the computer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AfkReason {
+    Idle,
+    Lock,
+    Suspend,
+}
+
+impl AfkReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AfkReason::Idle => "idle",
+            AfkReason::Lock => "lock",
+            AfkReason::Suspend => "suspend",
+        }
+    }
+}
+
 #[cfg(target_os = "windows")]
 pub fn get_idle_seconds() -> u64 {
     use windows::Win32::UI::Input::KeyboardAndMouse::{GetLastInputInfo, LASTINPUTINFO};
@@ -44,3 +62,28 @@ pub fn get_idle_seconds() -> u64 {
 pub fn get_idle_seconds() -> u64 {
     0
 }
+
+#[cfg(target_os = "windows")]
+pub fn is_session_locked() -> bool {
+    use windows::Win32::Foundation::BOOL;
+    use windows::Win32::System::StationsAndDesktops::{
+        CloseDesktop, OpenInputDesktop, SwitchDesktop, DESKTOP_CONTROL_FLAGS, DESKTOP_SWITCHDESKTOP,
+    };
+
+    // The input desktop can't be opened or switched to while the lock screen is up
+    unsafe {
+        match OpenInputDesktop(DESKTOP_CONTROL_FLAGS(0), BOOL(0), DESKTOP_SWITCHDESKTOP) {
+            Ok(desktop) => {
+                let locked = SwitchDesktop(desktop).is_err();
+                let _ = CloseDesktop(desktop);
+                locked
+            }
+            Err(_) => true,
+        }
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+pub fn is_session_locked() -> bool {
+    false
+}
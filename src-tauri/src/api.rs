@@ -0,0 +1,232 @@
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use serde_json::{json, Value};
+use tauri::Manager;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::control::{self, Command};
+use crate::error::{self, Error, ErrorCode};
+use crate::settings::Settings;
+use crate::{lock, metrics, paths};
+
+/// The opt-in HTTP API on 127.0.0.1.
+pub struct Api {
+    running: Mutex<Option<Running>>,
+    /// Whether `/metrics` is served.
+    metrics: AtomicBool,
+}
+
+struct Running {
+    port: u16,
+    server: Arc<Server>,
+}
+
+/// What a successful request is answered with.
+enum Body {
+    Json(Value),
+    /// Prometheus text format.
+    Metrics(String),
+}
+
+/// An error answered with `status` and `{"error": message}`.
+struct ApiError {
+    status: u16,
+    message: String,
+}
+
+impl ApiError {
+    fn new(status: u16, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<Error> for ApiError {
+    fn from(e: Error) -> Self {
+        let status = match e.code {
+            ErrorCode::Invalid => 400,
+            ErrorCode::NotFound => 404,
+            ErrorCode::Conflict | ErrorCode::Constraint => 409,
+            ErrorCode::Busy => 503,
+            ErrorCode::Database | ErrorCode::Io | ErrorCode::Internal => 500,
+        };
+        Self::new(status, e.message)
+    }
+}
+
+impl Api {
+    pub fn new() -> Self {
+        Self {
+            running: Mutex::new(None),
+            metrics: AtomicBool::new(false),
+        }
+    }
+
+    /// Starts, stops or restarts the server to match `settings`.
+    pub fn configure(&self, app: &tauri::AppHandle, settings: &Settings) {
+        self.metrics.store(settings.api_metrics, Ordering::SeqCst);
+        let mut running = lock(&self.running);
+        let wanted = settings.api_enabled.then_some(settings.api_port);
+        if running.as_ref().map(|r| r.port) == wanted {
+            return;
+        }
+        if let Some(old) = running.take() {
+            old.server.unblock();
+            log::info!("HTTP API on port {} stopped", old.port);
+        }
+        let Some(port) = wanted else {
+            return;
+        };
+        match start(app.clone(), port) {
+            Ok(server) => *running = Some(Running { port, server }),
+            Err(e) => log::error!("Could not start the HTTP API on port {}: {}", port, e),
+        }
+    }
+}
+
+/// `api-token` in the data directory. Requests must send its content as
+/// `Authorization: Bearer <token>`.
+pub fn token_path() -> PathBuf {
+    paths::data_dir().join("api-token")
+}
+
+/// Reads the token, creating a random one on first use. Delete the file to
+/// get a new one.
+pub fn token() -> error::Result<String> {
+    paths::secret(&token_path())
+}
+
+fn start(app: tauri::AppHandle, port: u16) -> error::Result<Arc<Server>> {
+    let token = token()?;
+    let server = Arc::new(Server::http(("127.0.0.1", port)).map_err(|e| Error::io(e.to_string()))?);
+    let listener = server.clone();
+    std::thread::spawn(move || {
+        // Ends when `unblock` is called on the server
+        for request in listener.incoming_requests() {
+            handle(&app, &token, request);
+        }
+    });
+    log::info!("HTTP API listening on 127.0.0.1:{}", port);
+    Ok(server)
+}
+
+fn handle(app: &tauri::AppHandle, token: &str, mut request: Request) {
+    let result = if authorized(&request, token) {
+        route(app, &mut request)
+    } else {
+        Err(ApiError::new(401, "Missing or wrong API token"))
+    };
+    let (status, content_type, body) = match result {
+        Ok(Body::Json(value)) => (200, "application/json", value.to_string()),
+        Ok(Body::Metrics(text)) => (200, "text/plain; version=0.0.4", text),
+        Err(e) => (e.status, "application/json", json!({ "error": e.message }).to_string()),
+    };
+    let content_type = Header::from_bytes("Content-Type", content_type).expect("static header is valid");
+    let response = Response::from_string(body)
+        .with_status_code(status)
+        .with_header(content_type);
+    if let Err(e) = request.respond(response) {
+        log::warn!("Failed to answer an API request: {}", e);
+    }
+}
+
+fn authorized(request: &Request, token: &str) -> bool {
+    let sent = request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv("Authorization"))
+        .and_then(|h| h.value.as_str().strip_prefix("Bearer "))
+        .unwrap_or("");
+    // Compare in constant time so the token can't be guessed byte by byte
+    sent.len() == token.len() && sent.bytes().zip(token.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Splits `/path?a=1&b=2` into the path and its query parameters.
+fn split_url(url: &str) -> (&str, Vec<(&str, &str)>) {
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+    let params = query
+        .split('&')
+        .filter(|p| !p.is_empty())
+        .map(|p| p.split_once('=').unwrap_or((p, "")))
+        .collect();
+    (path.trim_end_matches('/'), params)
+}
+
+fn param<'a>(params: &[(&str, &'a str)], name: &str) -> Option<&'a str> {
+    params.iter().find(|(k, _)| *k == name).map(|(_, v)| *v)
+}
+
+fn route(app: &tauri::AppHandle, request: &mut Request) -> Result<Body, ApiError> {
+    let url = request.url().to_string();
+    let (path, params) = split_url(&url);
+    // The snooze body is read from `request` while matching
+    let method = request.method().clone();
+
+    let command = match (method, path) {
+        (Method::Get, "/metrics") => return metrics(app),
+        (Method::Get, "/api/status") => Command::Status,
+        (Method::Get, "/api/history/today") => Command::History,
+        (Method::Get, "/api/stats") => Command::Stats {
+            date: param(&params, "date").map(str::to_string),
+        },
+        (Method::Post, "/api/stretch") => Command::Stretch,
+        (Method::Post, "/api/skip") => Command::Skip,
+        (Method::Post, "/api/treadmill/start") => Command::TreadmillStart,
+        (Method::Post, "/api/treadmill/stop") => Command::TreadmillStop,
+        (Method::Post, "/api/treadmill/toggle") => Command::TreadmillToggle,
+        (Method::Post, "/api/snooze") => Command::Snooze {
+            minutes: snooze_minutes(request, &params)?,
+        },
+        (_, "/metrics" | "/api/status" | "/api/history/today" | "/api/stats") => {
+            return Err(ApiError::new(405, "Use GET"))
+        }
+        (
+            _,
+            "/api/stretch" | "/api/skip" | "/api/treadmill/start" | "/api/treadmill/stop" | "/api/treadmill/toggle"
+            | "/api/snooze",
+        ) => {
+            return Err(ApiError::new(405, "Use POST"))
+        }
+        _ => return Err(ApiError::new(404, format!("No such endpoint: {}", path))),
+    };
+    Ok(Body::Json(control::execute(app, command)?))
+}
+
+fn metrics(app: &tauri::AppHandle) -> Result<Body, ApiError> {
+    if !app.state::<Api>().metrics.load(Ordering::SeqCst) {
+        return Err(ApiError::new(404, "Metrics are turned off in the settings"));
+    }
+    metrics::gather(app).map(Body::Metrics).map_err(|e| ApiError::new(500, e))
+}
+
+/// Minutes from `?minutes=` or a `{"minutes": n}` body, if given.
+fn snooze_minutes(request: &mut Request, params: &[(&str, &str)]) -> Result<Option<u64>, ApiError> {
+    if let Some(raw) = param(params, "minutes") {
+        return raw
+            .parse()
+            .map(Some)
+            .map_err(|_| ApiError::new(400, "minutes must be a whole number"));
+    }
+    let mut body = String::new();
+    request
+        .as_reader()
+        .take(4096)
+        .read_to_string(&mut body)
+        .map_err(|e| ApiError::new(400, e.to_string()))?;
+    if body.trim().is_empty() {
+        return Ok(None);
+    }
+    let value: Value = serde_json::from_str(&body).map_err(|e| ApiError::new(400, format!("Invalid JSON: {}", e)))?;
+    match value.get("minutes") {
+        None => Ok(None),
+        Some(m) => m
+            .as_u64()
+            .map(Some)
+            .ok_or_else(|| ApiError::new(400, "minutes must be a whole number")),
+    }
+}
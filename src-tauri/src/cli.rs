@@ -0,0 +1,281 @@
+use std::io::Write;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+use crate::control::Command;
+use crate::ipc::{self, Reply};
+
+/// How often `status --follow` asks the app.
+const FOLLOW_INTERVAL: Duration = Duration::from_secs(1);
+
+const USAGE: &str = "Usage: stretchia [--json|--waybar] <command>
+
+Commands:
+  status [--follow]        Show the timer; --follow keeps printing it as it changes
+  stretch                  Log a stretch and reset the timer
+  skip                     Log a skipped break and reset the timer
+  treadmill start|stop|toggle
+                           Start or end a walking session
+  snooze [MINUTES]         Hold reminders back (default 15 minutes)
+  undo                     Revert the last stretch, skip or treadmill action
+  history                  List today's activity
+  stats [YYYY-MM-DD]       Show the statistics of a day (default today)
+  show [settings|stats]    Bring a window of the app to the front
+
+Commands are sent to the running app. --json prints its reply as JSON,
+--waybar prints the status as a waybar custom module line.
+--data-dir <path> and --portable pick the data directory, as for the app.
+Without a command the app itself starts.";
+
+/// How `status` is printed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Human,
+    Json,
+    Waybar,
+}
+
+/// Runs the command given on the command line against the running app.
+/// Returns the exit code, or `None` if there is no command and the app should
+/// start instead.
+pub fn main() -> Option<i32> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let mut format = Format::Human;
+    let mut follow = false;
+    let mut words = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--json" => format = Format::Json,
+            "--waybar" => format = Format::Waybar,
+            "--follow" => follow = true,
+            "-h" | "--help" => words.push("help"),
+            // Pick the data directory, and with it the instance to talk to
+            "--data-dir" => {
+                iter.next();
+            }
+            "--portable" => {}
+            other if other.starts_with("--data-dir=") => {}
+            other if other.starts_with('-') => {
+                attach_console();
+                eprintln!("Unknown option: {}\n\n{}", other, USAGE);
+                return Some(2);
+            }
+            other => words.push(other),
+        }
+    }
+    if words.is_empty() {
+        return None;
+    }
+
+    attach_console();
+    if words == ["help"] {
+        println!("{}", USAGE);
+        return Some(0);
+    }
+    let command = match Command::from_words(&words) {
+        Ok(command) => command,
+        Err(message) => {
+            eprintln!("{}\n\n{}", message, USAGE);
+            return Some(2);
+        }
+    };
+    if (follow || format == Format::Waybar) && !matches!(command, Command::Status) {
+        eprintln!("--follow and --waybar only go with status\n\n{}", USAGE);
+        return Some(2);
+    }
+    if follow {
+        return Some(follow_status(format));
+    }
+
+    match ipc::send(&command) {
+        Ok(Reply::Ok(value)) => {
+            match format {
+                Format::Human => print_human(&command, &value),
+                Format::Json => println!("{}", value),
+                Format::Waybar => println!("{}", waybar(Some(&value))),
+            }
+            Some(0)
+        }
+        Ok(Reply::Error(e)) => {
+            if format != Format::Human {
+                println!("{}", serde_json::json!({ "error": e }));
+            } else {
+                eprintln!("{}", e.message);
+            }
+            Some(1)
+        }
+        Err(e) => {
+            eprintln!("Stretchia is not running ({})", e);
+            Some(1)
+        }
+    }
+}
+
+fn format_duration(total_s: u64) -> String {
+    format!("{}:{:02}:{:02}", total_s / 3600, total_s / 60 % 60, total_s % 60)
+}
+
+/// Wall-clock time of `ts` in the zone it was recorded in.
+fn format_time(ts: i64, offset_s: i64) -> String {
+    chrono::DateTime::from_timestamp(ts + offset_s, 0)
+        .map(|t| t.format("%H:%M").to_string())
+        .unwrap_or_default()
+}
+
+fn int(value: &Value, key: &str) -> i64 {
+    value.get(key).and_then(Value::as_i64).unwrap_or(0)
+}
+
+fn status_line(status: &Value) -> String {
+    let mode = status.get("mode").and_then(Value::as_str).unwrap_or("");
+    let stage = status.get("stage").and_then(Value::as_str).unwrap_or("");
+    let mut line = format!("{} {} ({})", mode, format_duration(int(status, "elapsed_s") as u64), stage);
+    if status.get("is_afk").and_then(Value::as_bool) == Some(true) {
+        line.push_str(", away");
+    }
+    if let Some(until) = status.get("snoozed_until").and_then(Value::as_i64) {
+        let offset = crate::tz::offset_at(until) as i64;
+        line.push_str(&format!(", snoozed until {}", format_time(until, offset)));
+    }
+    line
+}
+
+/// A line for waybar's `custom` module: the sitting time as text, the full
+/// status as tooltip, the stage as class and the mode (or `away`/`snoozed`)
+/// as alt for `format-icons`. `None` is the app not running.
+fn waybar(status: Option<&Value>) -> Value {
+    let Some(status) = status else {
+        return json!({
+            "text": "",
+            "tooltip": "Stretchia is not running",
+            "class": "stopped",
+            "alt": "stopped",
+        });
+    };
+    let elapsed_s = int(status, "elapsed_s");
+    let alt = if status.get("is_afk").and_then(Value::as_bool) == Some(true) {
+        "away".to_string()
+    } else if status.get("snoozed_until").is_some_and(|v| !v.is_null()) {
+        "snoozed".to_string()
+    } else {
+        status.get("mode").and_then(Value::as_str).unwrap_or("").to_lowercase()
+    };
+    json!({
+        "text": format!("{}:{:02}", elapsed_s / 3600, elapsed_s / 60 % 60),
+        "tooltip": status_line(status),
+        "class": status.get("stage").and_then(Value::as_str).unwrap_or(""),
+        "alt": alt,
+    })
+}
+
+/// Prints the status whenever it changes, for status bars that read a
+/// command's output line by line. Waits for the app while it isn't running,
+/// and ends when whoever reads the output goes away.
+fn follow_status(format: Format) -> i32 {
+    let mut last = None;
+    loop {
+        let status = match ipc::send(&Command::Status) {
+            Ok(Reply::Ok(value)) => Some(value),
+            Ok(Reply::Error(_)) | Err(_) => None,
+        };
+        let line = match (format, &status) {
+            (Format::Waybar, status) => waybar(status.as_ref()).to_string(),
+            (Format::Json, Some(status)) => status.to_string(),
+            (Format::Json, None) => "null".to_string(),
+            (Format::Human, Some(status)) => status_line(status),
+            (Format::Human, None) => "Stretchia is not running".to_string(),
+        };
+        if last.as_ref() != Some(&line) {
+            let mut stdout = std::io::stdout().lock();
+            if writeln!(stdout, "{}", line).and_then(|_| stdout.flush()).is_err() {
+                return 0;
+            }
+            last = Some(line);
+        }
+        std::thread::sleep(FOLLOW_INTERVAL);
+    }
+}
+
+fn print_human(command: &Command, value: &Value) {
+    match command {
+        Command::History => {
+            let workouts = value.as_array().map(Vec::as_slice).unwrap_or_default();
+            if workouts.is_empty() {
+                println!("Nothing logged today");
+            }
+            for w in workouts {
+                let kind = w.get("workout_type").and_then(Value::as_str).unwrap_or("");
+                let at = format_time(int(w, "started_at"), int(w, "utc_offset_s"));
+                let sat = int(w, "sitting_before_s") / 60;
+                match kind {
+                    "treadmill" => println!("{}  {:<9}  {} min, sat {} min before", at, kind, int(w, "duration_s") / 60, sat),
+                    _ => println!("{}  {:<9}  sat {} min before", at, kind, sat),
+                }
+            }
+        }
+        Command::Stats { .. } => println!("{}", stats_text(value)),
+        _ => println!("{}", status_line(value)),
+    }
+}
+
+fn stats_text(stats: &Value) -> String {
+    let duration = |key| format_duration(int(stats, key) as u64);
+    // The average is the only fractional field
+    let avg_sitting_s = stats.get("avg_sitting_before_s").and_then(Value::as_f64).unwrap_or(0.0).round() as u64;
+    [
+        stats.get("date").and_then(Value::as_str).unwrap_or("").to_string(),
+        format!("Stretches:      {}", int(stats, "stretch_count")),
+        format!("Treadmill:      {} ({})", int(stats, "treadmill_count"), duration("treadmill_total_s")),
+        format!("Active:         {}", duration("active_s")),
+        format!("AFK:            {}", duration("afk_s")),
+        format!("Avg sitting:    {}", format_duration(avg_sitting_s)),
+        format!("Longest streak: {}", duration("max_sitting_before_s")),
+    ]
+    .join("\n")
+}
+
+/// Release builds on Windows have no console of their own; borrow the one of
+/// the terminal we were started from so output shows up there.
+pub(crate) fn attach_console() {
+    #[cfg(windows)]
+    {
+        use windows::Win32::System::Console::{AttachConsole, ATTACH_PARENT_PROCESS};
+        unsafe {
+            let _ = AttachConsole(ATTACH_PARENT_PROCESS);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::DayStats;
+
+    #[test]
+    fn stats_show_the_average_sitting_time() {
+        let stats = serde_json::to_value(DayStats {
+            date: "2024-05-02".to_string(),
+            stretch_count: 3,
+            treadmill_count: 1,
+            treadmill_total_s: 1800,
+            active_s: 25_200,
+            afk_s: 3600,
+            avg_sitting_before_s: 2699.6,
+            max_sitting_before_s: 4500,
+            workouts: Vec::new(),
+        })
+        .unwrap();
+        assert_eq!(
+            stats_text(&stats),
+            "2024-05-02\n\
+             Stretches:      3\n\
+             Treadmill:      1 (0:30:00)\n\
+             Active:         7:00:00\n\
+             AFK:            1:00:00\n\
+             Avg sitting:    0:45:00\n\
+             Longest streak: 1:15:00"
+        );
+    }
+}
@@ -0,0 +1,144 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tauri::{Emitter, Manager};
+
+use crate::db_worker::DbWorker;
+use crate::error::{self, Error};
+use crate::settings::Settings;
+use crate::timer::Mode;
+use crate::{actions, db, lock, open_settings_window, open_stats_window, tick_payload, tz, SharedState};
+
+/// Snooze length when a request doesn't name one.
+pub const DEFAULT_SNOOZE_MIN: u64 = 15;
+
+/// Something a remote client (the HTTP API, the command line or MQTT) asks the
+/// running app to do. Each one runs the same code as its `cmd_*` command.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum Command {
+    Status,
+    History,
+    Stats { date: Option<String> },
+    Stretch,
+    Skip,
+    TreadmillStart,
+    TreadmillStop,
+    /// Starts the treadmill, or stops it if it is running.
+    TreadmillToggle,
+    Snooze { minutes: Option<u64> },
+    Undo,
+    /// Brings a window to the front: `settings`, `stats`, or the timer if
+    /// none is named.
+    Show { window: Option<String> },
+}
+
+impl Command {
+    /// Parses the words of a command line, e.g. `["treadmill", "start"]` or
+    /// `["snooze", "20"]`.
+    pub fn from_words(words: &[&str]) -> Result<Command, String> {
+        let command = match words {
+            ["status"] => Command::Status,
+            ["stretch"] => Command::Stretch,
+            ["skip"] => Command::Skip,
+            ["undo"] => Command::Undo,
+            ["history"] => Command::History,
+            ["treadmill", "start"] => Command::TreadmillStart,
+            ["treadmill", "stop"] => Command::TreadmillStop,
+            ["treadmill", "toggle"] => Command::TreadmillToggle,
+            ["snooze"] => Command::Snooze { minutes: None },
+            ["snooze", minutes] => Command::Snooze {
+                minutes: Some(minutes.parse().map_err(|_| format!("Not a number of minutes: {}", minutes))?),
+            },
+            ["show"] => Command::Show { window: None },
+            ["show", window] => Command::Show {
+                window: Some(window.to_string()),
+            },
+            ["stats"] => Command::Stats { date: None },
+            ["stats", date] => Command::Stats {
+                date: Some(date.to_string()),
+            },
+            _ => return Err(format!("Unknown command: {}", words.join(" "))),
+        };
+        Ok(command)
+    }
+}
+
+fn to_json(value: impl Serialize) -> error::Result<Value> {
+    Ok(serde_json::to_value(value)?)
+}
+
+/// Runs `command`, blocking until it is done. Actions answer with the timer
+/// state after them.
+pub fn execute(app: &tauri::AppHandle, command: Command) -> error::Result<Value> {
+    let worker = app.state::<DbWorker>().inner().clone();
+    let state = app.state::<SharedState>().inner().clone();
+    let block_on = tauri::async_runtime::block_on;
+
+    match command {
+        Command::Status => {}
+        Command::History => return to_json(block_on(worker.call(db::get_today_workouts))?),
+        Command::Stats { date } => {
+            let stats = block_on(worker.call(move |conn| {
+                let date = match date {
+                    Some(date) => date,
+                    None => {
+                        let now = chrono::Utc::now().timestamp();
+                        db::day_of(now, tz::offset_at(now), Settings::load(conn)?.day_start_s())
+                    }
+                };
+                db::get_stats_for_date(conn, &date)
+            }))?;
+            return to_json(stats);
+        }
+        Command::Stretch => {
+            block_on(actions::stretch(&worker, &state))?;
+            let _ = app.emit("history-changed", ());
+        }
+        Command::Skip => {
+            block_on(actions::skip(&worker, &state))?;
+            let _ = app.emit("history-changed", ());
+        }
+        Command::TreadmillStart => {
+            if lock(&state).timer.mode == Mode::Treadmill {
+                return Err(Error::conflict("Treadmill is already running"));
+            }
+            actions::start_treadmill(&worker, &state);
+        }
+        Command::TreadmillStop => {
+            if lock(&state).timer.mode != Mode::Treadmill {
+                return Err(Error::conflict("Treadmill is not running"));
+            }
+            block_on(actions::stop_treadmill(&worker, &state))?;
+            let _ = app.emit("history-changed", ());
+        }
+        Command::TreadmillToggle => {
+            if lock(&state).timer.mode == Mode::Treadmill {
+                block_on(actions::stop_treadmill(&worker, &state))?;
+                let _ = app.emit("history-changed", ());
+            } else {
+                actions::start_treadmill(&worker, &state);
+            }
+        }
+        Command::Snooze { minutes } => {
+            actions::snooze(&state, minutes.unwrap_or(DEFAULT_SNOOZE_MIN))?;
+        }
+        Command::Undo => {
+            if !block_on(actions::undo(&worker, &state))? {
+                return Err(Error::conflict("Nothing to undo"));
+            }
+            let _ = app.emit("history-changed", ());
+        }
+        Command::Show { window } => match window.as_deref() {
+            None | Some("timer") => {
+                if let Some(w) = app.get_webview_window("main") {
+                    let _ = w.show();
+                    let _ = w.set_focus();
+                }
+            }
+            Some("settings") => open_settings_window(app),
+            Some("stats") => open_stats_window(app),
+            Some(other) => return Err(Error::invalid(format!("No such window: {}", other))),
+        },
+    }
+    to_json(tick_payload(&lock(&state)))
+}
@@ -1,7 +1,12 @@
-use rusqlite::{Connection, params};
+use chrono::{Local, TimeZone};
+use rusqlite::{Connection, OptionalExtension, params};
 use serde::Serialize;
 use std::path::PathBuf;
 
+use crate::error::{self, Error};
+use crate::settings::{self, Settings};
+use crate::{paths, tz};
+
 #[derive(Debug, Serialize, Clone)]
 pub struct Workout {
     pub id: i64,
@@ -10,6 +15,11 @@ pub struct Workout {
     pub ended_at: i64,
     pub duration_s: i64,
     pub sitting_before_s: i64,
+    /// UTC offset in effect where and when the workout was recorded. Days are
+    /// bucketed by it, so travelling or a DST change never moves past entries.
+    pub utc_offset_s: i32,
+    /// IANA time zone the workout was recorded in, if it could be determined.
+    pub tz: Option<String>,
 }
 
 #[derive(Debug, Serialize, Clone)]
@@ -18,22 +28,21 @@ pub struct Setting {
     pub value: String,
 }
 
-pub fn db_path() -> PathBuf {
-    let base = if cfg!(target_os = "windows") {
-        std::env::var("APPDATA")
-            .map(PathBuf::from)
-            .unwrap_or_else(|_| dirs_fallback())
-    } else {
-        dirs_fallback()
-    };
-    base.join("Stretchia")
+fn workout_from_row(row: &rusqlite::Row) -> rusqlite::Result<Workout> {
+    Ok(Workout {
+        id: row.get(0)?,
+        workout_type: row.get(1)?,
+        started_at: row.get(2)?,
+        ended_at: row.get(3)?,
+        duration_s: row.get(4)?,
+        sitting_before_s: row.get(5)?,
+        utc_offset_s: row.get(6)?,
+        tz: row.get(7)?,
+    })
 }
 
-fn dirs_fallback() -> PathBuf {
-    let home = std::env::var("HOME")
-        .or_else(|_| std::env::var("USERPROFILE"))
-        .unwrap_or_else(|_| ".".to_string());
-    PathBuf::from(home)
+pub fn db_path() -> PathBuf {
+    paths::data_dir().to_path_buf()
 }
 
 pub fn initialize() -> rusqlite::Result<Connection> {
@@ -42,6 +51,28 @@ pub fn initialize() -> rusqlite::Result<Connection> {
     let db_file = dir.join("data.db");
     let conn = Connection::open(db_file)?;
 
+    // WAL lets readers run alongside the writer; with it, NORMAL sync only
+    // risks the last transactions on power loss, never corruption.
+    conn.pragma_update(None, "journal_mode", "WAL")?;
+    conn.pragma_update(None, "synchronous", "NORMAL")?;
+    conn.busy_timeout(std::time::Duration::from_secs(5))?;
+
+    migrate(&conn, &Local)?;
+
+    Ok(conn)
+}
+
+/// A fresh database in memory, for tests.
+#[cfg(test)]
+pub fn open_in_memory() -> Connection {
+    let conn = Connection::open_in_memory().unwrap();
+    migrate(&conn, &Local).unwrap();
+    conn
+}
+
+/// Creates the tables and brings databases from older versions up to date.
+/// Rows from before offsets were stored are placed in `zone`.
+fn migrate<Z: TimeZone>(conn: &Connection, zone: &Z) -> rusqlite::Result<()> {
     conn.execute_batch(
         "CREATE TABLE IF NOT EXISTS workouts (
             id               INTEGER PRIMARY KEY AUTOINCREMENT,
@@ -52,6 +83,20 @@ pub fn initialize() -> rusqlite::Result<Connection> {
             sitting_before_s INTEGER NOT NULL
         );
 
+        CREATE TABLE IF NOT EXISTS workout_undo (
+            id               INTEGER PRIMARY KEY AUTOINCREMENT,
+            batch            INTEGER NOT NULL,
+            action           TEXT NOT NULL,
+            workout_id       INTEGER NOT NULL,
+            type             TEXT NOT NULL,
+            started_at       INTEGER NOT NULL,
+            ended_at         INTEGER NOT NULL,
+            duration_s       INTEGER NOT NULL,
+            sitting_before_s INTEGER NOT NULL,
+            deleted_at       INTEGER,
+            created_at       INTEGER NOT NULL
+        );
+
         CREATE TABLE IF NOT EXISTS computer_usage (
             id       INTEGER PRIMARY KEY AUTOINCREMENT,
             date     TEXT NOT NULL UNIQUE,
@@ -59,90 +104,261 @@ pub fn initialize() -> rusqlite::Result<Connection> {
             afk_s    INTEGER NOT NULL DEFAULT 0
         );
 
+        CREATE TABLE IF NOT EXISTS computer_usage_hourly (
+            date     TEXT NOT NULL,
+            hour     INTEGER NOT NULL,
+            active_s INTEGER NOT NULL DEFAULT 0,
+            afk_s    INTEGER NOT NULL DEFAULT 0,
+            PRIMARY KEY (date, hour)
+        );
+
+        CREATE TABLE IF NOT EXISTS sessions (
+            id         INTEGER PRIMARY KEY AUTOINCREMENT,
+            kind       TEXT NOT NULL,
+            started_at INTEGER NOT NULL,
+            ended_at   INTEGER NOT NULL,
+            reason     TEXT
+        );
+
         CREATE TABLE IF NOT EXISTS settings (
             key   TEXT PRIMARY KEY,
             value TEXT NOT NULL
-        );"
-    )?;
-
-    // Seed defaults if not present
-    let defaults = [
-        ("afk_threshold_min", "5"),
-        ("warn_at_min", "45"),
-        ("shake_at_min", "75"),
-        ("window_opacity", "0.8"),
-        ("history_dots_count", "10"),
-        ("window_anchor", "top-right"),
-    ];
-    for (k, v) in defaults {
+        );
+
+        CREATE TABLE IF NOT EXISTS settings_history (
+            id           INTEGER PRIMARY KEY AUTOINCREMENT,
+            batch        INTEGER NOT NULL,
+            key          TEXT NOT NULL,
+            old_value    TEXT,
+            new_value    TEXT NOT NULL,
+            source       TEXT NOT NULL,
+            changed_at   INTEGER NOT NULL,
+            utc_offset_s INTEGER NOT NULL
+        );
+
+        CREATE TABLE IF NOT EXISTS webhook_deliveries (
+            id              INTEGER PRIMARY KEY AUTOINCREMENT,
+            url             TEXT NOT NULL,
+            event           TEXT NOT NULL,
+            payload         TEXT NOT NULL,
+            created_at      INTEGER NOT NULL,
+            status          TEXT NOT NULL DEFAULT 'pending',
+            attempts        INTEGER NOT NULL DEFAULT 0,
+            next_attempt_at INTEGER NOT NULL,
+            response_code   INTEGER,
+            error           TEXT
+        );
+
+        CREATE INDEX IF NOT EXISTS webhook_deliveries_due
+            ON webhook_deliveries (status, next_attempt_at);"
+    )?;
+
+    add_column_if_missing(conn, "workouts", "deleted_at", "INTEGER")?;
+    for table in ["workouts", "workout_undo", "sessions", "computer_usage", "computer_usage_hourly"] {
+        add_column_if_missing(conn, table, "utc_offset_s", "INTEGER")?;
+        add_column_if_missing(conn, table, "tz", "TEXT")?;
+    }
+    backfill_utc_offsets(conn, zone)?;
+    hide_logged_passwords(conn)
+}
+
+fn add_column_if_missing(conn: &Connection, table: &str, column: &str, decl: &str) -> rusqlite::Result<()> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let exists = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .collect::<rusqlite::Result<Vec<_>>>()?
+        .iter()
+        .any(|name| name == column);
+    if !exists {
+        conn.execute_batch(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, decl))?;
+    }
+    Ok(())
+}
+
+/// Passwords used to be logged in the clear; keep only whether one was set.
+fn hide_logged_passwords(conn: &Connection) -> rusqlite::Result<()> {
+    for field in settings::FIELDS.iter().filter(|f| Settings::is_password(f.key)) {
         conn.execute(
-            "INSERT OR IGNORE INTO settings (key, value) VALUES (?1, ?2)",
-            params![k, v],
+            "UPDATE settings_history
+             SET old_value = CASE WHEN old_value <> '' THEN ?2 ELSE old_value END,
+                 new_value = CASE WHEN new_value <> '' THEN ?2 ELSE new_value END
+             WHERE key = ?1",
+            params![field.key, settings::HIDDEN],
         )?;
     }
+    Ok(())
+}
 
-    Ok(conn)
+/// Rows written before offsets were stored get the offset `zone` had at the
+/// time. With the local zone that is the best guess left, and it matches how
+/// they were bucketed so far.
+fn backfill_utc_offsets<Z: TimeZone>(conn: &Connection, zone: &Z) -> rusqlite::Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    for table in ["workouts", "workout_undo", "sessions"] {
+        let rows = tx
+            .prepare(&format!("SELECT id, started_at FROM {} WHERE utc_offset_s IS NULL", table))?
+            .query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?)))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        for (id, started_at) in rows {
+            tx.execute(
+                &format!("UPDATE {} SET utc_offset_s = ?2 WHERE id = ?1", table),
+                params![id, tz::offset_in(zone, started_at)],
+            )?;
+        }
+    }
+    // Usage rows are keyed by the local date and hour they were counted in
+    // already, so only the offset is missing
+    for table in ["computer_usage", "computer_usage_hourly"] {
+        tx.execute(
+            &format!("UPDATE {} SET utc_offset_s = ?1 WHERE utc_offset_s IS NULL", table),
+            params![tz::offset_in(zone, chrono::Utc::now().timestamp())],
+        )?;
+    }
+    tx.commit()
 }
 
-pub fn record_stretch(conn: &Connection, sitting_before_s: i64) -> rusqlite::Result<()> {
+pub fn record_stretch(conn: &Connection, sitting_before_s: i64) -> rusqlite::Result<i64> {
     let now = chrono::Utc::now().timestamp();
     conn.execute(
-        "INSERT INTO workouts (type, started_at, ended_at, duration_s, sitting_before_s)
-         VALUES ('stretch', ?1, ?2, 300, ?3)",
-        params![now, now + 300, sitting_before_s],
+        "INSERT INTO workouts (type, started_at, ended_at, duration_s, sitting_before_s, utc_offset_s, tz)
+         VALUES ('stretch', ?1, ?2, 300, ?3, ?4, ?5)",
+        params![now, now + 300, sitting_before_s, tz::offset_at(now), tz::current_zone()],
     )?;
-    Ok(())
+    Ok(conn.last_insert_rowid())
 }
 
-pub fn record_skip(conn: &Connection, sitting_before_s: i64) -> rusqlite::Result<()> {
+pub fn record_skip(conn: &Connection, sitting_before_s: i64) -> rusqlite::Result<i64> {
     let now = chrono::Utc::now().timestamp();
     conn.execute(
-        "INSERT INTO workouts (type, started_at, ended_at, duration_s, sitting_before_s)
-         VALUES ('skip', ?1, ?1, 0, ?2)",
-        params![now, sitting_before_s],
+        "INSERT INTO workouts (type, started_at, ended_at, duration_s, sitting_before_s, utc_offset_s, tz)
+         VALUES ('skip', ?1, ?1, 0, ?2, ?3, ?4)",
+        params![now, sitting_before_s, tz::offset_at(now), tz::current_zone()],
     )?;
-    Ok(())
+    Ok(conn.last_insert_rowid())
 }
 
-pub fn record_treadmill(conn: &Connection, started_at: i64, duration_s: i64, sitting_before_s: i64) -> rusqlite::Result<()> {
+pub fn record_treadmill(conn: &Connection, started_at: i64, duration_s: i64, sitting_before_s: i64) -> rusqlite::Result<i64> {
     let ended_at = started_at + duration_s;
     conn.execute(
-        "INSERT INTO workouts (type, started_at, ended_at, duration_s, sitting_before_s)
-         VALUES ('treadmill', ?1, ?2, ?3, ?4)",
-        params![started_at, ended_at, duration_s, sitting_before_s],
+        "INSERT INTO workouts (type, started_at, ended_at, duration_s, sitting_before_s, utc_offset_s, tz)
+         VALUES ('treadmill', ?1, ?2, ?3, ?4, ?5, ?6)",
+        params![started_at, ended_at, duration_s, sitting_before_s, tz::offset_at(started_at), tz::current_zone()],
     )?;
-    Ok(())
+    Ok(conn.last_insert_rowid())
+}
+
+/// The day (`YYYY-MM-DD`) a moment counts towards: its local date, with the
+/// hours before the day start still belonging to the previous day.
+pub fn day_of(ts: i64, utc_offset_s: i32, day_start_s: i64) -> String {
+    chrono::DateTime::from_timestamp(ts + utc_offset_s as i64 - day_start_s, 0)
+        .unwrap_or_default()
+        .format("%Y-%m-%d")
+        .to_string()
 }
 
 pub fn get_today_workouts(conn: &Connection) -> rusqlite::Result<Vec<Workout>> {
-    let today = chrono::Local::now().format("%Y-%m-%d").to_string();
+    let now = chrono::Utc::now().timestamp();
+    let day_start = Settings::load(conn)?.day_start_s();
+    let today = day_of(now, tz::offset_at(now), day_start);
     let mut stmt = conn.prepare(
-        "SELECT id, type, started_at, ended_at, duration_s, sitting_before_s
+        "SELECT id, type, started_at, ended_at, duration_s, sitting_before_s, utc_offset_s, tz
          FROM workouts
-         WHERE date(started_at, 'unixepoch', 'localtime') = ?1
+         WHERE date(started_at + utc_offset_s - ?2, 'unixepoch') = ?1 AND deleted_at IS NULL
          ORDER BY started_at"
     )?;
-    let rows = stmt.query_map(params![today], |row| {
-        Ok(Workout {
-            id: row.get(0)?,
-            workout_type: row.get(1)?,
-            started_at: row.get(2)?,
-            ended_at: row.get(3)?,
-            duration_s: row.get(4)?,
-            sitting_before_s: row.get(5)?,
+    let rows = stmt.query_map(params![today, day_start], workout_from_row)?;
+    rows.collect()
+}
+
+/// Adds to a date's usage, both the daily totals and the given hour's bucket.
+/// Summing a date's hourly rows gives back its daily totals. `date` is the day
+/// as returned by `day_of` and `hour` the wall-clock hour, both in the zone
+/// the usage was counted in, which is stored along with it.
+pub fn update_computer_usage(
+    conn: &Connection,
+    date: &str,
+    hour: u32,
+    active_delta: i64,
+    afk_delta: i64,
+    utc_offset_s: i32,
+    tz: Option<&str>,
+) -> rusqlite::Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    tx.execute(
+        "INSERT INTO computer_usage (date, active_s, afk_s, utc_offset_s, tz) VALUES (?1, ?2, ?3, ?4, ?5)
+         ON CONFLICT(date) DO UPDATE SET active_s = active_s + ?2, afk_s = afk_s + ?3,
+             utc_offset_s = ?4, tz = ?5",
+        params![date, active_delta, afk_delta, utc_offset_s, tz],
+    )?;
+    tx.execute(
+        "INSERT INTO computer_usage_hourly (date, hour, active_s, afk_s, utc_offset_s, tz)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+         ON CONFLICT(date, hour) DO UPDATE SET active_s = active_s + ?3, afk_s = afk_s + ?4,
+             utc_offset_s = ?5, tz = ?6",
+        params![date, hour, active_delta, afk_delta, utc_offset_s, tz],
+    )?;
+    tx.commit()
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct HourlyUsage {
+    pub date: String,
+    pub hour: u32,
+    pub active_s: i64,
+    pub afk_s: i64,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct HourOfDayUsage {
+    pub hour: u32,
+    pub active_s: i64,
+    pub afk_s: i64,
+    /// Days in the range with any usage recorded in this hour.
+    pub days: i64,
+}
+
+/// Usage per date and hour between `from` and `to` (inclusive, `YYYY-MM-DD`).
+/// Hours without activity are omitted.
+pub fn get_hourly_usage(conn: &Connection, from: &str, to: &str) -> rusqlite::Result<Vec<HourlyUsage>> {
+    let mut stmt = conn.prepare(
+        "SELECT date, hour, active_s, afk_s
+         FROM computer_usage_hourly
+         WHERE date BETWEEN ?1 AND ?2
+         ORDER BY date, hour"
+    )?;
+    let rows = stmt.query_map(params![from, to], |row| {
+        Ok(HourlyUsage {
+            date: row.get(0)?,
+            hour: row.get(1)?,
+            active_s: row.get(2)?,
+            afk_s: row.get(3)?,
         })
     })?;
     rows.collect()
 }
 
-pub fn update_computer_usage(conn: &Connection, active_delta: i64, afk_delta: i64) -> rusqlite::Result<()> {
-    let today = chrono::Local::now().format("%Y-%m-%d").to_string();
-    conn.execute(
-        "INSERT INTO computer_usage (date, active_s, afk_s) VALUES (?1, ?2, ?3)
-         ON CONFLICT(date) DO UPDATE SET active_s = active_s + ?2, afk_s = afk_s + ?3",
-        params![today, active_delta, afk_delta],
+/// Usage folded onto the 24 hours of the day across a date range, for
+/// hour-of-day heatmaps. Always returns one entry per hour.
+pub fn get_hour_of_day_usage(conn: &Connection, from: &str, to: &str) -> rusqlite::Result<Vec<HourOfDayUsage>> {
+    let mut hours: Vec<HourOfDayUsage> = (0..24)
+        .map(|hour| HourOfDayUsage { hour, active_s: 0, afk_s: 0, days: 0 })
+        .collect();
+    let mut stmt = conn.prepare(
+        "SELECT hour, SUM(active_s), SUM(afk_s), COUNT(DISTINCT date)
+         FROM computer_usage_hourly
+         WHERE date BETWEEN ?1 AND ?2
+         GROUP BY hour"
     )?;
-    Ok(())
+    let mut rows = stmt.query(params![from, to])?;
+    while let Some(row) = rows.next()? {
+        let hour: u32 = row.get(0)?;
+        if let Some(h) = hours.get_mut(hour as usize) {
+            h.active_s = row.get(1)?;
+            h.afk_s = row.get(2)?;
+            h.days = row.get(3)?;
+        }
+    }
+    Ok(hours)
 }
 
 pub fn load_settings(conn: &Connection) -> rusqlite::Result<Vec<Setting>> {
@@ -165,6 +381,124 @@ pub fn update_setting(conn: &Connection, key: &str, value: &str) -> rusqlite::Re
     Ok(())
 }
 
+#[derive(Debug, Serialize, Clone)]
+pub struct SettingChange {
+    pub key: String,
+    /// The default if the setting had never been saved before.
+    pub old_value: String,
+    pub new_value: String,
+}
+
+/// Settings changed together, e.g. by one click on Save.
+#[derive(Debug, Serialize, Clone)]
+pub struct SettingsChangeSet {
+    pub batch: i64,
+    /// `window`, `import` or `rollback`
+    pub source: String,
+    pub changed_at: i64,
+    pub utc_offset_s: i32,
+    pub changes: Vec<SettingChange>,
+}
+
+/// Writes changed settings and logs them as one batch. `changes` holds
+/// `(key, old, new)`; entries whose value didn't change are skipped.
+pub fn update_settings_logged(
+    conn: &Connection,
+    changes: &[(String, String, String)],
+    source: &str,
+) -> rusqlite::Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    let batch: i64 = tx.query_row("SELECT COALESCE(MAX(batch), 0) + 1 FROM settings_history", [], |row| row.get(0))?;
+    let now = chrono::Utc::now().timestamp();
+    for (key, old, new) in changes {
+        if old == new {
+            continue;
+        }
+        update_setting(&tx, key, new)?;
+        tx.execute(
+            "INSERT INTO settings_history (batch, key, old_value, new_value, source, changed_at, utc_offset_s)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                batch,
+                key,
+                Settings::for_history(key, old),
+                Settings::for_history(key, new),
+                source,
+                now,
+                tz::offset_at(now)
+            ],
+        )?;
+    }
+    tx.commit()
+}
+
+/// Logged settings changes, newest first.
+pub fn get_settings_history(conn: &Connection, limit: i64) -> rusqlite::Result<Vec<SettingsChangeSet>> {
+    let mut stmt = conn.prepare(
+        "SELECT batch, source, changed_at, utc_offset_s, key, old_value, new_value
+         FROM settings_history
+         WHERE batch IN (SELECT DISTINCT batch FROM settings_history ORDER BY batch DESC LIMIT ?1)
+         ORDER BY batch DESC, id"
+    )?;
+    let rows = stmt.query(params![limit])?;
+    group_setting_changes(rows)
+}
+
+/// Settings changes made on the given day (see `day_of`), oldest first.
+pub fn get_settings_history_for_date(conn: &Connection, date: &str) -> rusqlite::Result<Vec<SettingsChangeSet>> {
+    let mut stmt = conn.prepare(
+        "SELECT batch, source, changed_at, utc_offset_s, key, old_value, new_value
+         FROM settings_history
+         WHERE date(changed_at + utc_offset_s - ?2, 'unixepoch') = ?1
+         ORDER BY batch, id"
+    )?;
+    let rows = stmt.query(params![date, Settings::load(conn)?.day_start_s()])?;
+    group_setting_changes(rows)
+}
+
+/// Folds rows ordered by batch into one change set per batch.
+fn group_setting_changes(mut rows: rusqlite::Rows) -> rusqlite::Result<Vec<SettingsChangeSet>> {
+    let mut sets: Vec<SettingsChangeSet> = Vec::new();
+    while let Some(row) = rows.next()? {
+        let batch: i64 = row.get(0)?;
+        if sets.last().map(|s| s.batch) != Some(batch) {
+            sets.push(SettingsChangeSet {
+                batch,
+                source: row.get(1)?,
+                changed_at: row.get(2)?,
+                utc_offset_s: row.get(3)?,
+                changes: Vec::new(),
+            });
+        }
+        if let Some(set) = sets.last_mut() {
+            set.changes.push(SettingChange {
+                key: row.get(4)?,
+                old_value: row.get(5)?,
+                new_value: row.get(6)?,
+            });
+        }
+    }
+    Ok(sets)
+}
+
+/// Setting values as they were right after `batch`: the last value logged up
+/// to it, or for keys only changed later, the value before that change. Keys
+/// the history doesn't know about are left out.
+pub fn settings_after_batch(conn: &Connection, batch: i64) -> rusqlite::Result<Vec<(String, String)>> {
+    let mut stmt = conn.prepare(
+        "SELECT key, new_value FROM settings_history h
+         WHERE batch <= ?1
+           AND id = (SELECT MAX(id) FROM settings_history WHERE key = h.key AND batch <= ?1)
+         UNION ALL
+         SELECT key, old_value FROM settings_history h
+         WHERE batch > ?1
+           AND id = (SELECT MIN(id) FROM settings_history WHERE key = h.key AND batch > ?1)
+           AND NOT EXISTS (SELECT 1 FROM settings_history WHERE key = h.key AND batch <= ?1)"
+    )?;
+    let rows = stmt.query_map(params![batch], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    rows.collect()
+}
+
 #[derive(Debug, Serialize, Clone)]
 pub struct DayStats {
     pub date: String,
@@ -178,30 +512,51 @@ pub struct DayStats {
     pub workouts: Vec<Workout>,
 }
 
-pub fn get_stats_for_date(conn: &Connection, date: &str) -> rusqlite::Result<DayStats> {
+/// Stats for a day (see `day_of`). Workouts are listed and counted on the day
+/// they started, but time is split at day boundaries: a treadmill walk past
+/// the end of the day only adds the part inside it, and a sitting streak that
+/// began the day before only counts from the start of this one.
+pub fn get_stats_for_date(conn: &Connection, date: &str) -> error::Result<DayStats> {
+    let day_start = Settings::load(conn)?.day_start_s();
+    // Day bounds on the wall clock, shifted by the day start like `day_of` does
+    let day_from = chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .map_err(|_| Error::invalid(format!("Not a YYYY-MM-DD date: {}", date)))?
+        .and_time(chrono::NaiveTime::MIN)
+        .and_utc()
+        .timestamp();
+    let day_to = day_from + 86400;
+    let wall = |ts: i64, w: &Workout| ts + w.utc_offset_s as i64 - day_start;
+
     let mut stmt = conn.prepare(
-        "SELECT id, type, started_at, ended_at, duration_s, sitting_before_s
+        "SELECT id, type, started_at, ended_at, duration_s, sitting_before_s, utc_offset_s, tz
          FROM workouts
-         WHERE date(started_at, 'unixepoch', 'localtime') = ?1
+         WHERE started_at + utc_offset_s - ?1 < ?3
+           AND ended_at + utc_offset_s - ?1 >= ?2
+           AND deleted_at IS NULL
          ORDER BY started_at"
     )?;
-    let workouts: Vec<Workout> = stmt.query_map(params![date], |row| {
-        Ok(Workout {
-            id: row.get(0)?,
-            workout_type: row.get(1)?,
-            started_at: row.get(2)?,
-            ended_at: row.get(3)?,
-            duration_s: row.get(4)?,
-            sitting_before_s: row.get(5)?,
-        })
-    })?.collect::<rusqlite::Result<Vec<_>>>()?;
+    let overlapping: Vec<Workout> = stmt
+        .query_map(params![day_start, day_from, day_to], workout_from_row)?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let treadmill_total_s: i64 = overlapping
+        .iter()
+        .filter(|w| w.workout_type == "treadmill")
+        .map(|w| (wall(w.ended_at, w).min(day_to) - wall(w.started_at, w).max(day_from)).max(0))
+        .sum();
+
+    let workouts: Vec<Workout> = overlapping
+        .into_iter()
+        .filter(|w| wall(w.started_at, w) >= day_from)
+        .collect();
 
     let stretch_count = workouts.iter().filter(|w| w.workout_type == "stretch").count() as i64;
-    let treadmill_workouts: Vec<&Workout> = workouts.iter().filter(|w| w.workout_type == "treadmill").collect();
-    let treadmill_count = treadmill_workouts.len() as i64;
-    let treadmill_total_s: i64 = treadmill_workouts.iter().map(|w| w.duration_s).sum();
+    let treadmill_count = workouts.iter().filter(|w| w.workout_type == "treadmill").count() as i64;
 
-    let sitting_times: Vec<i64> = workouts.iter().map(|w| w.sitting_before_s).collect();
+    let sitting_times: Vec<i64> = workouts
+        .iter()
+        .map(|w| w.sitting_before_s.min(wall(w.started_at, w) - day_from))
+        .collect();
     let avg_sitting_before_s = if sitting_times.is_empty() {
         0.0
     } else {
@@ -228,11 +583,266 @@ pub fn get_stats_for_date(conn: &Connection, date: &str) -> rusqlite::Result<Day
     })
 }
 
-pub fn delete_workout(conn: &Connection, id: i64) -> rusqlite::Result<()> {
+/// Counts over all of history, for the metrics endpoint.
+#[derive(Debug, Clone, Default)]
+pub struct Totals {
+    pub stretch_count: i64,
+    pub skip_count: i64,
+    pub treadmill_s: i64,
+    pub active_s: i64,
+    pub afk_s: i64,
+}
+
+pub fn get_totals(conn: &Connection) -> rusqlite::Result<Totals> {
+    let (stretch_count, skip_count, treadmill_s) = conn.query_row(
+        "SELECT COALESCE(SUM(type = 'stretch'), 0),
+                COALESCE(SUM(type = 'skip'), 0),
+                COALESCE(SUM(CASE WHEN type = 'treadmill' THEN duration_s END), 0)
+         FROM workouts
+         WHERE deleted_at IS NULL",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+    )?;
+    let (active_s, afk_s) = conn.query_row(
+        "SELECT COALESCE(SUM(active_s), 0), COALESCE(SUM(afk_s), 0) FROM computer_usage",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    Ok(Totals {
+        stretch_count,
+        skip_count,
+        treadmill_s,
+        active_s,
+        afk_s,
+    })
+}
+
+/// Moves a workout to the trash. It stays restorable until purged.
+pub fn delete_workout(conn: &Connection, id: i64) -> error::Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    let deleted = get_workout(&tx, id)?;
+    if is_trashed(&tx, id)? {
+        return Err(Error::not_found(format!("No workout {} outside the trash", id)));
+    }
+    let batch = next_undo_batch(&tx)?;
+    snapshot_workout(&tx, batch, "delete", id)?;
+    tx.execute(
+        "UPDATE workouts SET deleted_at = ?2 WHERE id = ?1",
+        params![id, chrono::Utc::now().timestamp()],
+    )?;
+    // The streaks on both sides of the deleted entry join up
+    recompute_sitting_after(&tx, deleted.started_at, deleted.started_at, None, batch)?;
+    Ok(tx.commit()?)
+}
+
+fn is_trashed(conn: &Connection, id: i64) -> rusqlite::Result<bool> {
+    conn.query_row("SELECT deleted_at IS NOT NULL FROM workouts WHERE id = ?1", params![id], |row| row.get(0))
+}
+
+/// Removes a workout for good, bypassing the trash. Used when a timer action
+/// that has just recorded it is undone.
+pub fn remove_workout(conn: &Connection, id: i64) -> rusqlite::Result<()> {
     conn.execute("DELETE FROM workouts WHERE id = ?1", params![id])?;
     Ok(())
 }
 
+pub fn get_workout(conn: &Connection, id: i64) -> error::Result<Workout> {
+    conn.query_row(
+        "SELECT id, type, started_at, ended_at, duration_s, sitting_before_s, utc_offset_s, tz
+         FROM workouts WHERE id = ?1",
+        params![id],
+        workout_from_row,
+    )
+    .optional()?
+    .ok_or_else(|| Error::not_found(format!("No workout {}", id)))
+}
+
+/// Inserts a workout that was not recorded live (e.g. a walk the user forgot to log).
+pub fn insert_workout(
+    conn: &Connection,
+    workout_type: &str,
+    started_at: i64,
+    ended_at: i64,
+    sitting_before_s: i64,
+) -> rusqlite::Result<i64> {
+    let tx = conn.unchecked_transaction()?;
+    tx.execute(
+        "INSERT INTO workouts (type, started_at, ended_at, duration_s, sitting_before_s, utc_offset_s, tz)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            workout_type,
+            started_at,
+            ended_at,
+            ended_at - started_at,
+            sitting_before_s,
+            tz::offset_at(started_at),
+            tz::current_zone(),
+        ],
+    )?;
+    let id = tx.last_insert_rowid();
+    let batch = next_undo_batch(&tx)?;
+    snapshot_workout(&tx, batch, "insert", id)?;
+    recompute_sitting_after(&tx, started_at, started_at, Some(id), batch)?;
+    tx.commit()?;
+    Ok(id)
+}
+
+pub fn update_workout(
+    conn: &Connection,
+    id: i64,
+    workout_type: &str,
+    started_at: i64,
+    ended_at: i64,
+    sitting_before_s: i64,
+) -> error::Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    let old = get_workout(&tx, id)?;
+    let batch = next_undo_batch(&tx)?;
+    snapshot_workout(&tx, batch, "edit", id)?;
+    // An entry recorded elsewhere keeps its zone; one from the current zone
+    // gets the offset of its new time, which may be across a DST change.
+    let zone = tz::current_zone();
+    let utc_offset_s = if old.tz.is_none() || old.tz == zone {
+        tz::offset_at(started_at)
+    } else {
+        old.utc_offset_s
+    };
+    tx.execute(
+        "UPDATE workouts
+         SET type = ?2, started_at = ?3, ended_at = ?4, duration_s = ?5, sitting_before_s = ?6,
+             utc_offset_s = ?7, tz = COALESCE(tz, ?8)
+         WHERE id = ?1",
+        params![id, workout_type, started_at, ended_at, ended_at - started_at, sitting_before_s, utc_offset_s, zone],
+    )?;
+    recompute_sitting_after(
+        &tx,
+        old.started_at.min(started_at),
+        old.started_at.max(started_at),
+        Some(id),
+        batch,
+    )?;
+    Ok(tx.commit()?)
+}
+
+/// Returns the first workout that started after `ts`, if any.
+pub fn next_workout_after(conn: &Connection, ts: i64) -> rusqlite::Result<Option<Workout>> {
+    conn.query_row(
+        "SELECT id, type, started_at, ended_at, duration_s, sitting_before_s, utc_offset_s, tz
+         FROM workouts
+         WHERE started_at > ?1 AND deleted_at IS NULL
+         ORDER BY started_at, id
+         LIMIT 1",
+        params![ts],
+        workout_from_row,
+    ).optional()
+}
+
+pub fn get_latest_workout(conn: &Connection) -> rusqlite::Result<Option<Workout>> {
+    conn.query_row(
+        "SELECT id, type, started_at, ended_at, duration_s, sitting_before_s, utc_offset_s, tz
+         FROM workouts
+         WHERE deleted_at IS NULL
+         ORDER BY started_at DESC, id DESC
+         LIMIT 1",
+        [],
+        workout_from_row,
+    ).optional()
+}
+
+/// Moment a workout restarted the sitting timer. Stretches and skips reset it
+/// on click, treadmill sessions once the walk is over.
+pub fn sitting_reset_at(w: &Workout) -> i64 {
+    if w.workout_type == "treadmill" {
+        w.ended_at
+    } else {
+        w.started_at
+    }
+}
+
+/// Estimates the sitting time before a manual entry starting at `started_at`.
+/// The sitting streak recorded by the following workout is split at the new
+/// entry; without a following workout the still open streak is split instead.
+pub fn estimate_sitting_before(
+    conn: &Connection,
+    started_at: i64,
+    open_streak_s: i64,
+    open_streak_end: i64,
+) -> rusqlite::Result<i64> {
+    let (streak_s, streak_end) = match next_workout_after(conn, started_at)? {
+        Some(next) => (next.sitting_before_s, next.started_at),
+        None => (open_streak_s, open_streak_end),
+    };
+    Ok((streak_s - (streak_end - started_at)).max(0))
+}
+
+/// Keeps `sitting_before_s` consistent after the workouts between `from` and
+/// `to` changed. Each workout that follows is recomputed from the moment its
+/// predecessor reset the timer: the active time logged in `sessions` since then,
+/// never more than the wall-clock gap. Where the gap predates the session log
+/// only the stored value can be capped. `edited` keeps the value it was given
+/// and is only capped. Every changed row is snapshotted into `undo_batch`.
+pub fn recompute_sitting_after(
+    conn: &Connection,
+    from: i64,
+    to: i64,
+    edited: Option<i64>,
+    undo_batch: i64,
+) -> rusqlite::Result<()> {
+    let mut stmt = conn.prepare(
+        "SELECT id, type, started_at, ended_at, duration_s, sitting_before_s, utc_offset_s, tz
+         FROM workouts
+         WHERE deleted_at IS NULL
+           AND started_at >= COALESCE(
+               (SELECT MAX(started_at) FROM workouts WHERE started_at < ?1 AND deleted_at IS NULL), ?1)
+           AND started_at <= COALESCE(
+               (SELECT MIN(started_at) FROM workouts WHERE started_at > ?2 AND deleted_at IS NULL), ?2)
+         ORDER BY started_at, id"
+    )?;
+    let workouts = stmt
+        .query_map(params![from, to.max(from)], workout_from_row)?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    let settings = Settings::load(conn)?;
+    let logged_since: Option<i64> = conn.query_row("SELECT MIN(started_at) FROM sessions", [], |row| row.get(0))?;
+
+    for pair in workouts.windows(2) {
+        let (prev, cur) = (&pair[0], &pair[1]);
+        let mut reset_at = sitting_reset_at(prev);
+        if settings.reset_timer_on_new_day {
+            // The timer dropped the streak at the start of the day
+            let day_start = settings.day_start_s();
+            let offset = cur.utc_offset_s as i64;
+            let local = cur.started_at + offset - day_start;
+            reset_at = reset_at.max(local - local.rem_euclid(86400) - offset + day_start);
+        }
+        let gap = (cur.started_at - reset_at).max(0);
+        let sitting = match logged_since {
+            Some(since) if since <= reset_at && Some(cur.id) != edited => {
+                active_seconds_between(conn, reset_at, cur.started_at)?.min(gap)
+            }
+            _ => cur.sitting_before_s.min(gap),
+        };
+        if sitting != cur.sitting_before_s {
+            snapshot_workout(conn, undo_batch, "recompute", cur.id)?;
+            conn.execute(
+                "UPDATE workouts SET sitting_before_s = ?2 WHERE id = ?1",
+                params![cur.id, sitting],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Seconds logged as active between `from` and `to`.
+fn active_seconds_between(conn: &Connection, from: i64, to: i64) -> rusqlite::Result<i64> {
+    conn.query_row(
+        "SELECT COALESCE(SUM(MIN(ended_at, ?2) - MAX(started_at, ?1)), 0)
+         FROM sessions
+         WHERE kind = 'active' AND started_at < ?2 AND ended_at > ?1",
+        params![from, to],
+        |row| row.get(0),
+    )
+}
+
 pub fn get_setting(conn: &Connection, key: &str) -> Option<String> {
     conn.query_row(
         "SELECT value FROM settings WHERE key = ?1",
@@ -240,3 +850,683 @@ pub fn get_setting(conn: &Connection, key: &str) -> Option<String> {
         |row| row.get(0),
     ).ok()
 }
+
+#[derive(Debug, Serialize, Clone)]
+pub struct TrashedWorkout {
+    #[serde(flatten)]
+    pub workout: Workout,
+    pub deleted_at: i64,
+}
+
+fn next_undo_batch(conn: &Connection) -> rusqlite::Result<i64> {
+    conn.query_row("SELECT COALESCE(MAX(batch), 0) + 1 FROM workout_undo", [], |row| row.get(0))
+}
+
+/// Saves the current state of a workout so the change about to be made to it
+/// can be reverted with `undo_last_change`.
+fn snapshot_workout(conn: &Connection, batch: i64, action: &str, id: i64) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT INTO workout_undo
+            (batch, action, workout_id, type, started_at, ended_at, duration_s, sitting_before_s,
+             utc_offset_s, tz, deleted_at, created_at)
+         SELECT ?1, ?2, id, type, started_at, ended_at, duration_s, sitting_before_s,
+             utc_offset_s, tz, deleted_at, ?3
+         FROM workouts WHERE id = ?4",
+        params![batch, action, chrono::Utc::now().timestamp(), id],
+    )?;
+    Ok(())
+}
+
+/// Reverts the most recent delete, restore, edit or manual insert, including the sitting
+/// times it adjusted. Returns the undone action, or `None` if there is nothing to undo.
+pub fn undo_last_change(conn: &Connection) -> rusqlite::Result<Option<String>> {
+    let tx = conn.unchecked_transaction()?;
+    let batch: Option<i64> = tx.query_row("SELECT MAX(batch) FROM workout_undo", [], |row| row.get(0))?;
+    let Some(batch) = batch else {
+        return Ok(None);
+    };
+
+    let mut action = None;
+    {
+        let mut stmt = tx.prepare(
+            "SELECT action, workout_id, type, started_at, ended_at, duration_s, sitting_before_s,
+                    utc_offset_s, tz, deleted_at
+             FROM workout_undo WHERE batch = ?1 ORDER BY id DESC",
+        )?;
+        let mut rows = stmt.query(params![batch])?;
+        while let Some(row) = rows.next()? {
+            let row_action: String = row.get(0)?;
+            let id: i64 = row.get(1)?;
+            if row_action == "insert" {
+                tx.execute("DELETE FROM workouts WHERE id = ?1", params![id])?;
+            } else {
+                tx.execute(
+                    "UPDATE workouts
+                     SET type = ?2, started_at = ?3, ended_at = ?4, duration_s = ?5,
+                         sitting_before_s = ?6, utc_offset_s = ?7, tz = ?8, deleted_at = ?9
+                     WHERE id = ?1",
+                    params![
+                        id,
+                        row.get::<_, String>(2)?,
+                        row.get::<_, i64>(3)?,
+                        row.get::<_, i64>(4)?,
+                        row.get::<_, i64>(5)?,
+                        row.get::<_, i64>(6)?,
+                        row.get::<_, i32>(7)?,
+                        row.get::<_, Option<String>>(8)?,
+                        row.get::<_, Option<i64>>(9)?,
+                    ],
+                )?;
+            }
+            if row_action != "recompute" {
+                action = Some(row_action);
+            }
+        }
+    }
+
+    tx.execute("DELETE FROM workout_undo WHERE batch = ?1", params![batch])?;
+    tx.commit()?;
+    Ok(action)
+}
+
+pub fn get_deleted_workouts(conn: &Connection) -> rusqlite::Result<Vec<TrashedWorkout>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, type, started_at, ended_at, duration_s, sitting_before_s, utc_offset_s, tz, deleted_at
+         FROM workouts
+         WHERE deleted_at IS NOT NULL
+         ORDER BY deleted_at DESC"
+    )?;
+    let rows = stmt.query_map([], |row| {
+        Ok(TrashedWorkout {
+            workout: workout_from_row(row)?,
+            deleted_at: row.get(8)?,
+        })
+    })?;
+    rows.collect()
+}
+
+/// Takes a workout back out of the trash, as one change `undo_last_change` can revert.
+pub fn restore_workout(conn: &Connection, id: i64) -> error::Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    let restored = get_workout(&tx, id)?;
+    if !is_trashed(&tx, id)? {
+        return Err(Error::not_found(format!("No workout {} in the trash", id)));
+    }
+    let batch = next_undo_batch(&tx)?;
+    snapshot_workout(&tx, batch, "restore", id)?;
+    tx.execute("UPDATE workouts SET deleted_at = NULL WHERE id = ?1", params![id])?;
+    recompute_sitting_after(&tx, restored.started_at, restored.started_at, Some(id), batch)?;
+    Ok(tx.commit()?)
+}
+
+/// Permanently removes workouts that have been in the trash for longer than
+/// `retention_days`, along with the undo batches that touch them and undo
+/// snapshots of the same age.
+pub fn purge_trash(conn: &Connection, retention_days: i64) -> rusqlite::Result<()> {
+    let cutoff = chrono::Utc::now().timestamp() - retention_days * 86400;
+    conn.execute(
+        "DELETE FROM workout_undo WHERE batch IN (
+             SELECT batch FROM workout_undo WHERE workout_id IN (
+                 SELECT id FROM workouts WHERE deleted_at IS NOT NULL AND deleted_at < ?1))",
+        params![cutoff],
+    )?;
+    conn.execute(
+        "DELETE FROM workouts WHERE deleted_at IS NOT NULL AND deleted_at < ?1",
+        params![cutoff],
+    )?;
+    conn.execute("DELETE FROM workout_undo WHERE created_at < ?1", params![cutoff])?;
+    Ok(())
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct Session {
+    pub id: i64,
+    /// `active` or `afk`
+    pub kind: String,
+    pub started_at: i64,
+    pub ended_at: i64,
+    /// Why an AFK session started: `idle`, `lock` or `suspend`
+    pub reason: Option<String>,
+    pub utc_offset_s: i32,
+    pub tz: Option<String>,
+}
+
+pub fn start_session(conn: &Connection, kind: &str, reason: Option<&str>, started_at: i64, ended_at: i64) -> rusqlite::Result<i64> {
+    conn.execute(
+        "INSERT INTO sessions (kind, started_at, ended_at, reason, utc_offset_s, tz)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![kind, started_at, ended_at, reason, tz::offset_at(started_at), tz::current_zone()],
+    )?;
+    Ok(conn.last_insert_rowid())
+}
+
+pub fn extend_session(conn: &Connection, id: i64, ended_at: i64) -> rusqlite::Result<()> {
+    conn.execute(
+        "UPDATE sessions SET ended_at = ?2 WHERE id = ?1",
+        params![id, ended_at],
+    )?;
+    Ok(())
+}
+
+/// Active and AFK intervals overlapping the given day (see `day_of`), in the
+/// zone each was recorded in.
+pub fn get_sessions_for_date(conn: &Connection, date: &str) -> rusqlite::Result<Vec<Session>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, kind, started_at, ended_at, reason, utc_offset_s, tz
+         FROM sessions
+         WHERE date(started_at + utc_offset_s - ?2, 'unixepoch') <= ?1
+           AND date(ended_at + utc_offset_s - ?2, 'unixepoch') >= ?1
+         ORDER BY started_at"
+    )?;
+    let rows = stmt.query_map(params![date, Settings::load(conn)?.day_start_s()], |row| {
+        Ok(Session {
+            id: row.get(0)?,
+            kind: row.get(1)?,
+            started_at: row.get(2)?,
+            ended_at: row.get(3)?,
+            reason: row.get(4)?,
+            utc_offset_s: row.get(5)?,
+            tz: row.get(6)?,
+        })
+    })?;
+    rows.collect()
+}
+
+/// One event sent to one webhook URL. The table is both the queue and the
+/// delivery log.
+#[derive(Debug, Serialize, Clone)]
+pub struct WebhookDelivery {
+    pub id: i64,
+    pub url: String,
+    pub event: String,
+    pub payload: String,
+    pub created_at: i64,
+    /// `pending`, `delivered` or `failed`
+    pub status: String,
+    pub attempts: i64,
+    pub next_attempt_at: i64,
+    /// HTTP status of the last attempt, if it got an answer.
+    pub response_code: Option<i64>,
+    pub error: Option<String>,
+}
+
+fn delivery_from_row(row: &rusqlite::Row) -> rusqlite::Result<WebhookDelivery> {
+    Ok(WebhookDelivery {
+        id: row.get(0)?,
+        url: row.get(1)?,
+        event: row.get(2)?,
+        payload: row.get(3)?,
+        created_at: row.get(4)?,
+        status: row.get(5)?,
+        attempts: row.get(6)?,
+        next_attempt_at: row.get(7)?,
+        response_code: row.get(8)?,
+        error: row.get(9)?,
+    })
+}
+
+const DELIVERY_COLUMNS: &str =
+    "id, url, event, payload, created_at, status, attempts, next_attempt_at, response_code, error";
+
+pub fn queue_webhook_delivery(conn: &Connection, url: &str, event: &str, payload: &str) -> rusqlite::Result<i64> {
+    let now = chrono::Utc::now().timestamp();
+    conn.execute(
+        "INSERT INTO webhook_deliveries (url, event, payload, created_at, next_attempt_at)
+         VALUES (?1, ?2, ?3, ?4, ?4)",
+        params![url, event, payload, now],
+    )?;
+    Ok(conn.last_insert_rowid())
+}
+
+/// Pending deliveries whose next attempt is due, oldest first.
+pub fn due_webhook_deliveries(conn: &Connection, now: i64, limit: i64) -> rusqlite::Result<Vec<WebhookDelivery>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM webhook_deliveries
+         WHERE status = 'pending' AND next_attempt_at <= ?1
+         ORDER BY id LIMIT ?2",
+        DELIVERY_COLUMNS
+    ))?;
+    let rows = stmt.query_map(params![now, limit], delivery_from_row)?;
+    rows.collect()
+}
+
+/// Records the outcome of an attempt. `next_attempt_at` is `None` once the
+/// delivery succeeded or was given up on, depending on `status`.
+pub fn record_webhook_attempt(
+    conn: &Connection,
+    id: i64,
+    status: &str,
+    next_attempt_at: Option<i64>,
+    response_code: Option<u16>,
+    error: Option<&str>,
+) -> rusqlite::Result<()> {
+    conn.execute(
+        "UPDATE webhook_deliveries
+         SET status = ?2, attempts = attempts + 1, next_attempt_at = COALESCE(?3, next_attempt_at),
+             response_code = ?4, error = ?5
+         WHERE id = ?1",
+        params![id, status, next_attempt_at, response_code, error],
+    )?;
+    Ok(())
+}
+
+/// The delivery log, newest first.
+pub fn get_webhook_deliveries(conn: &Connection, limit: i64) -> rusqlite::Result<Vec<WebhookDelivery>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM webhook_deliveries ORDER BY id DESC LIMIT ?1",
+        DELIVERY_COLUMNS
+    ))?;
+    let rows = stmt.query_map(params![limit], delivery_from_row)?;
+    rows.collect()
+}
+
+/// Forgets finished deliveries older than `retention_days`.
+pub fn purge_webhook_deliveries(conn: &Connection, retention_days: i64) -> rusqlite::Result<()> {
+    let cutoff = chrono::Utc::now().timestamp() - retention_days * 86400;
+    conn.execute(
+        "DELETE FROM webhook_deliveries WHERE status != 'pending' AND created_at < ?1",
+        params![cutoff],
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, FixedOffset, Local};
+
+    use super::*;
+    use crate::usage::UsageBuffer;
+
+    const BERLIN_WINTER: i32 = 3600;
+    const BERLIN_SUMMER: i32 = 7200;
+    const NEW_YORK_SUMMER: i32 = -4 * 3600;
+
+    fn ts(utc: &str) -> i64 {
+        DateTime::parse_from_rfc3339(utc).unwrap().timestamp()
+    }
+
+    fn set_day_start(conn: &Connection, hour: u32) {
+        Settings { day_start_hour: hour, ..Settings::default() }.save(conn, "test").unwrap();
+    }
+
+    /// A stretch recorded at `started_at` in a zone that was `utc_offset_s` ahead of UTC.
+    fn stretch(conn: &Connection, started_at: i64, utc_offset_s: i32, zone: &str) {
+        conn.execute(
+            "INSERT INTO workouts (type, started_at, ended_at, duration_s, sitting_before_s, utc_offset_s, tz)
+             VALUES ('stretch', ?1, ?1 + 300, 300, 0, ?2, ?3)",
+            params![started_at, utc_offset_s, zone],
+        )
+        .unwrap();
+    }
+
+    fn stretches_on(conn: &Connection, date: &str) -> i64 {
+        get_stats_for_date(conn, date).unwrap().stretch_count
+    }
+
+    /// Counts a minute of activity at `utc` the way the tick loop does, in a
+    /// zone `utc_offset_s` ahead of UTC, and returns the date it was filed under.
+    fn count_usage(conn: &Connection, utc: &str, utc_offset_s: i32, day_start_s: i64) -> String {
+        let offset = FixedOffset::east_opt(utc_offset_s).unwrap();
+        let at = DateTime::<Local>::from_naive_utc_and_offset(
+            DateTime::from_timestamp(ts(utc), 0).unwrap().naive_utc(),
+            offset,
+        );
+        let mut buffer = UsageBuffer::new();
+        buffer.add(at, day_start_s, 60, 0);
+        let delta = buffer.take().unwrap();
+        update_computer_usage(conn, &delta.date, delta.hour, delta.active_s, delta.afk_s, delta.utc_offset_s, None)
+            .unwrap();
+        delta.date
+    }
+
+    #[test]
+    fn day_of_follows_dst_transitions() {
+        // Spring forward in Berlin: 02:00 CET becomes 03:00 CEST at 01:00 UTC
+        assert_eq!(day_of(ts("2024-03-30T22:30:00Z"), BERLIN_WINTER, 0), "2024-03-30");
+        assert_eq!(day_of(ts("2024-03-30T23:30:00Z"), BERLIN_WINTER, 0), "2024-03-31");
+        assert_eq!(day_of(ts("2024-03-31T01:30:00Z"), BERLIN_SUMMER, 0), "2024-03-31");
+        // 03:30 CEST is before a 04:00 day start, 04:30 CEST after it
+        assert_eq!(day_of(ts("2024-03-31T01:30:00Z"), BERLIN_SUMMER, 4 * 3600), "2024-03-30");
+        assert_eq!(day_of(ts("2024-03-31T02:30:00Z"), BERLIN_SUMMER, 4 * 3600), "2024-03-31");
+
+        // Fall back: 03:00 CEST becomes 02:00 CET at 01:00 UTC, so 02:30 happens twice
+        assert_eq!(day_of(ts("2024-10-26T21:30:00Z"), BERLIN_SUMMER, 0), "2024-10-26");
+        assert_eq!(day_of(ts("2024-10-27T00:30:00Z"), BERLIN_SUMMER, 0), "2024-10-27");
+        assert_eq!(day_of(ts("2024-10-27T01:30:00Z"), BERLIN_WINTER, 0), "2024-10-27");
+        assert_eq!(day_of(ts("2024-10-27T02:30:00Z"), BERLIN_WINTER, 4 * 3600), "2024-10-26");
+        assert_eq!(day_of(ts("2024-10-27T03:30:00Z"), BERLIN_WINTER, 4 * 3600), "2024-10-27");
+    }
+
+    #[test]
+    fn day_of_uses_the_zone_of_the_record() {
+        let moment = ts("2024-06-01T23:30:00Z");
+        assert_eq!(day_of(moment, BERLIN_SUMMER, 0), "2024-06-02");
+        assert_eq!(day_of(moment, NEW_YORK_SUMMER, 0), "2024-06-01");
+        assert_eq!(day_of(moment, BERLIN_SUMMER, 4 * 3600), "2024-06-01");
+    }
+
+    #[test]
+    fn stats_count_workouts_on_the_day_of_their_zone() {
+        let conn = open_in_memory();
+        // 01:30 in Berlin and 19:30 the evening before in New York
+        stretch(&conn, ts("2024-06-01T23:30:00Z"), BERLIN_SUMMER, "Europe/Berlin");
+        stretch(&conn, ts("2024-06-01T23:30:00Z"), NEW_YORK_SUMMER, "America/New_York");
+        // 05:00 in Berlin and 23:00 the evening before in New York
+        stretch(&conn, ts("2024-06-02T03:00:00Z"), BERLIN_SUMMER, "Europe/Berlin");
+        stretch(&conn, ts("2024-06-02T03:00:00Z"), NEW_YORK_SUMMER, "America/New_York");
+
+        assert_eq!(stretches_on(&conn, "2024-06-01"), 2);
+        assert_eq!(stretches_on(&conn, "2024-06-02"), 2);
+
+        set_day_start(&conn, 4);
+        assert_eq!(stretches_on(&conn, "2024-06-01"), 3);
+        assert_eq!(stretches_on(&conn, "2024-06-02"), 1);
+    }
+
+    #[test]
+    fn stats_count_workouts_across_dst_transitions() {
+        let conn = open_in_memory();
+        // 23:30 CET, then 01:30 CET and 03:30 CEST around spring forward
+        stretch(&conn, ts("2024-03-30T22:30:00Z"), BERLIN_WINTER, "Europe/Berlin");
+        stretch(&conn, ts("2024-03-31T00:30:00Z"), BERLIN_WINTER, "Europe/Berlin");
+        stretch(&conn, ts("2024-03-31T01:30:00Z"), BERLIN_SUMMER, "Europe/Berlin");
+        // 02:30 CEST, then 02:30 and 03:30 CET around fall back
+        stretch(&conn, ts("2024-10-27T00:30:00Z"), BERLIN_SUMMER, "Europe/Berlin");
+        stretch(&conn, ts("2024-10-27T01:30:00Z"), BERLIN_WINTER, "Europe/Berlin");
+        stretch(&conn, ts("2024-10-27T02:30:00Z"), BERLIN_WINTER, "Europe/Berlin");
+
+        assert_eq!(stretches_on(&conn, "2024-03-30"), 1);
+        assert_eq!(stretches_on(&conn, "2024-03-31"), 2);
+        assert_eq!(stretches_on(&conn, "2024-10-26"), 0);
+        assert_eq!(stretches_on(&conn, "2024-10-27"), 3);
+
+        set_day_start(&conn, 4);
+        assert_eq!(stretches_on(&conn, "2024-03-30"), 3);
+        assert_eq!(stretches_on(&conn, "2024-03-31"), 0);
+        assert_eq!(stretches_on(&conn, "2024-10-26"), 3);
+        assert_eq!(stretches_on(&conn, "2024-10-27"), 0);
+    }
+
+    #[test]
+    fn usage_is_filed_under_the_day_of_its_zone() {
+        let conn = open_in_memory();
+        assert_eq!(count_usage(&conn, "2024-06-01T23:30:00Z", BERLIN_SUMMER, 0), "2024-06-02");
+        assert_eq!(count_usage(&conn, "2024-06-01T23:30:00Z", NEW_YORK_SUMMER, 0), "2024-06-01");
+        assert_eq!(count_usage(&conn, "2024-06-01T23:30:00Z", BERLIN_SUMMER, 4 * 3600), "2024-06-01");
+        // The repeated hour after fall back still counts towards the same day
+        assert_eq!(count_usage(&conn, "2024-10-27T00:30:00Z", BERLIN_SUMMER, 0), "2024-10-27");
+        assert_eq!(count_usage(&conn, "2024-10-27T01:30:00Z", BERLIN_WINTER, 0), "2024-10-27");
+        assert_eq!(count_usage(&conn, "2024-10-27T01:30:00Z", BERLIN_WINTER, 4 * 3600), "2024-10-26");
+
+        assert_eq!(get_stats_for_date(&conn, "2024-06-01").unwrap().active_s, 120);
+        assert_eq!(get_stats_for_date(&conn, "2024-06-02").unwrap().active_s, 60);
+        assert_eq!(get_stats_for_date(&conn, "2024-10-27").unwrap().active_s, 120);
+        assert_eq!(get_stats_for_date(&conn, "2024-10-26").unwrap().active_s, 60);
+    }
+
+    /// 2024-06-01 00:00 UTC
+    const T: i64 = 1_717_200_000;
+
+    /// A workout inserted as is, without recomputing its neighbours.
+    fn workout(conn: &Connection, workout_type: &str, started_at: i64, sitting_before_s: i64) -> i64 {
+        conn.execute(
+            "INSERT INTO workouts (type, started_at, ended_at, duration_s, sitting_before_s, utc_offset_s, tz)
+             VALUES (?1, ?2, ?2 + 300, 300, ?3, 0, 'UTC')",
+            params![workout_type, started_at, sitting_before_s],
+        )
+        .unwrap();
+        conn.last_insert_rowid()
+    }
+
+    fn active(conn: &Connection, started_at: i64, ended_at: i64) {
+        start_session(conn, "active", None, started_at, ended_at).unwrap();
+    }
+
+    fn sitting(conn: &Connection, id: i64) -> i64 {
+        get_workout(conn, id).unwrap().sitting_before_s
+    }
+
+    /// Rows in the batch `undo_last_change` reverts next.
+    fn latest_batch_len(conn: &Connection) -> i64 {
+        conn.query_row(
+            "SELECT COUNT(*) FROM workout_undo WHERE batch = (SELECT MAX(batch) FROM workout_undo)",
+            [],
+            |row| row.get(0),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn editing_recomputes_the_next_streak_from_the_session_log() {
+        let conn = open_in_memory();
+        active(&conn, T, T + 4000);
+        active(&conn, T + 4500, T + 10_000);
+        workout(&conn, "stretch", T + 1000, 1000);
+        let moved = workout(&conn, "stretch", T + 3000, 2000);
+        let next = workout(&conn, "stretch", T + 6000, 2500);
+
+        update_workout(&conn, moved, "stretch", T + 2000, T + 2300, 1000).unwrap();
+        assert_eq!(sitting(&conn, moved), 1000);
+        // 2000 s active up to the break, then 1500 s after it
+        assert_eq!(sitting(&conn, next), 3500);
+
+        assert_eq!(undo_last_change(&conn).unwrap().as_deref(), Some("edit"));
+        assert_eq!(get_workout(&conn, moved).unwrap().started_at, T + 3000);
+        assert_eq!(sitting(&conn, moved), 2000);
+        assert_eq!(sitting(&conn, next), 2500);
+    }
+
+    #[test]
+    fn editing_before_the_session_log_only_caps() {
+        let conn = open_in_memory();
+        workout(&conn, "stretch", T, 600);
+        let moved = workout(&conn, "stretch", T + 3000, 3000);
+        let next = workout(&conn, "stretch", T + 5000, 2000);
+
+        update_workout(&conn, moved, "stretch", T + 4000, T + 4300, 5000).unwrap();
+        assert_eq!(sitting(&conn, moved), 4000);
+        assert_eq!(sitting(&conn, next), 1000);
+
+        // Without a log there is nothing to raise it from
+        update_workout(&conn, moved, "stretch", T + 3000, T + 3300, 3000).unwrap();
+        assert_eq!(sitting(&conn, moved), 3000);
+        assert_eq!(sitting(&conn, next), 1000);
+    }
+
+    #[test]
+    fn inserting_between_two_breaks_splits_the_streak() {
+        let conn = open_in_memory();
+        active(&conn, T, T + 10_000);
+        workout(&conn, "stretch", T + 1000, 1000);
+        let next = workout(&conn, "stretch", T + 5000, 4000);
+
+        let estimate = estimate_sitting_before(&conn, T + 3000, 0, 0).unwrap();
+        assert_eq!(estimate, 2000);
+        let id = insert_workout(&conn, "stretch", T + 3000, T + 3300, estimate).unwrap();
+        assert_eq!(sitting(&conn, id), 2000);
+        assert_eq!(sitting(&conn, next), 2000);
+
+        assert_eq!(undo_last_change(&conn).unwrap().as_deref(), Some("insert"));
+        assert!(get_workout(&conn, id).is_err());
+        assert_eq!(sitting(&conn, next), 4000);
+    }
+
+    #[test]
+    fn estimate_after_the_latest_break_splits_the_open_streak() {
+        let conn = open_in_memory();
+        workout(&conn, "stretch", T, 0);
+        // Sitting for 1500 s up to T + 9000, of which the last 1000 s follow the new entry
+        assert_eq!(estimate_sitting_before(&conn, T + 8000, 1500, T + 9000).unwrap(), 500);
+        assert_eq!(estimate_sitting_before(&conn, T + 7000, 1500, T + 9000).unwrap(), 0);
+    }
+
+    #[test]
+    fn deleting_breaks_joins_the_streaks() {
+        let conn = open_in_memory();
+        active(&conn, T, T + 10_000);
+        let first = workout(&conn, "stretch", T + 1000, 1000);
+        let middle = workout(&conn, "stretch", T + 3000, 2000);
+        let last = workout(&conn, "stretch", T + 6000, 3000);
+
+        delete_workout(&conn, middle).unwrap();
+        assert_eq!(sitting(&conn, last), 5000);
+
+        // The latest break has no streak after it to recompute
+        delete_workout(&conn, last).unwrap();
+        assert_eq!(get_latest_workout(&conn).unwrap().unwrap().id, first);
+        assert_eq!(latest_batch_len(&conn), 1);
+
+        undo_last_change(&conn).unwrap();
+        assert_eq!(get_latest_workout(&conn).unwrap().unwrap().id, last);
+        undo_last_change(&conn).unwrap();
+        assert_eq!(sitting(&conn, middle), 2000);
+        assert_eq!(sitting(&conn, last), 3000);
+    }
+
+    #[test]
+    fn deleted_workouts_leave_the_stats_for_the_trash() {
+        let conn = open_in_memory();
+        workout(&conn, "stretch", T + 1000, 0);
+        let deleted = workout(&conn, "stretch", T + 3000, 0);
+        assert_eq!(stretches_on(&conn, "2024-06-01"), 2);
+
+        delete_workout(&conn, deleted).unwrap();
+        assert_eq!(stretches_on(&conn, "2024-06-01"), 1);
+        let trash = get_deleted_workouts(&conn).unwrap();
+        assert_eq!(trash.iter().map(|t| t.workout.id).collect::<Vec<_>>(), [deleted]);
+        assert!(delete_workout(&conn, deleted).is_err());
+
+        restore_workout(&conn, deleted).unwrap();
+        assert_eq!(stretches_on(&conn, "2024-06-01"), 2);
+        assert!(get_deleted_workouts(&conn).unwrap().is_empty());
+        assert!(restore_workout(&conn, deleted).is_err());
+        // Restoring is undone like any other change
+        assert_eq!(undo_last_change(&conn).unwrap().as_deref(), Some("restore"));
+        assert_eq!(stretches_on(&conn, "2024-06-01"), 1);
+    }
+
+    #[test]
+    fn undo_reverts_the_latest_batch_only() {
+        let conn = open_in_memory();
+        active(&conn, T, T + 10_000);
+        workout(&conn, "stretch", T + 1000, 1000);
+        let edited = workout(&conn, "skip", T + 3000, 2000);
+        let middle = workout(&conn, "stretch", T + 5000, 2000);
+        let last = workout(&conn, "stretch", T + 7000, 2000);
+
+        update_workout(&conn, edited, "stretch", T + 3000, T + 3300, 2000).unwrap();
+        delete_workout(&conn, middle).unwrap();
+        // The delete and the streak it joined up
+        assert_eq!(latest_batch_len(&conn), 2);
+        assert_eq!(sitting(&conn, last), 4000);
+
+        assert_eq!(undo_last_change(&conn).unwrap().as_deref(), Some("delete"));
+        assert!(get_deleted_workouts(&conn).unwrap().is_empty());
+        assert_eq!(sitting(&conn, last), 2000);
+        assert_eq!(get_workout(&conn, edited).unwrap().workout_type, "stretch");
+
+        assert_eq!(undo_last_change(&conn).unwrap().as_deref(), Some("edit"));
+        assert_eq!(get_workout(&conn, edited).unwrap().workout_type, "skip");
+        assert_eq!(undo_last_change(&conn).unwrap(), None);
+    }
+
+    #[test]
+    fn purging_removes_only_expired_trash() {
+        let conn = open_in_memory();
+        let kept = workout(&conn, "stretch", T + 1000, 0);
+        let expired = workout(&conn, "stretch", T + 3000, 0);
+        let recent = workout(&conn, "stretch", T + 5000, 0);
+        delete_workout(&conn, expired).unwrap();
+        delete_workout(&conn, recent).unwrap();
+        let long_ago = chrono::Utc::now().timestamp() - 31 * 86400;
+        conn.execute("UPDATE workouts SET deleted_at = ?2 WHERE id = ?1", params![expired, long_ago])
+            .unwrap();
+
+        purge_trash(&conn, 30).unwrap();
+        assert!(get_workout(&conn, kept).is_ok());
+        assert!(get_workout(&conn, expired).is_err());
+        let trash = get_deleted_workouts(&conn).unwrap();
+        assert_eq!(trash.iter().map(|t| t.workout.id).collect::<Vec<_>>(), [recent]);
+        let snapshots = |id: i64| -> i64 {
+            conn.query_row("SELECT COUNT(*) FROM workout_undo WHERE workout_id = ?1", params![id], |row| row.get(0))
+                .unwrap()
+        };
+        assert_eq!(snapshots(expired), 0);
+        assert_eq!(snapshots(recent), 1);
+
+        // What is left to undo is the recent delete
+        assert_eq!(undo_last_change(&conn).unwrap().as_deref(), Some("delete"));
+        assert_eq!(undo_last_change(&conn).unwrap(), None);
+    }
+
+    #[test]
+    fn legacy_rows_get_the_offset_their_time_had() {
+        let berlin = chrono_tz::Europe::Berlin;
+        assert_eq!(tz::offset_in(&berlin, ts("2024-03-31T00:30:00Z")), BERLIN_WINTER);
+        assert_eq!(tz::offset_in(&berlin, ts("2024-03-31T01:30:00Z")), BERLIN_SUMMER);
+        assert_eq!(tz::offset_in(&berlin, ts("2024-10-27T00:30:00Z")), BERLIN_SUMMER);
+        assert_eq!(tz::offset_in(&berlin, ts("2024-10-27T01:30:00Z")), BERLIN_WINTER);
+
+        // A database from before offsets were stored
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE workouts (
+                id               INTEGER PRIMARY KEY AUTOINCREMENT,
+                type             TEXT NOT NULL,
+                started_at       INTEGER NOT NULL,
+                ended_at         INTEGER NOT NULL,
+                duration_s       INTEGER NOT NULL,
+                sitting_before_s INTEGER NOT NULL
+            );
+            CREATE TABLE computer_usage (
+                id       INTEGER PRIMARY KEY AUTOINCREMENT,
+                date     TEXT NOT NULL UNIQUE,
+                active_s INTEGER NOT NULL DEFAULT 0,
+                afk_s    INTEGER NOT NULL DEFAULT 0
+            );",
+        )
+        .unwrap();
+        for started_at in [ts("2024-03-31T00:30:00Z"), ts("2024-03-31T01:30:00Z")] {
+            conn.execute(
+                "INSERT INTO workouts (type, started_at, ended_at, duration_s, sitting_before_s)
+                 VALUES ('stretch', ?1, ?1 + 300, 300, 0)",
+                params![started_at],
+            )
+            .unwrap();
+        }
+        conn.execute("INSERT INTO computer_usage (date, active_s) VALUES ('2024-03-31', 60)", []).unwrap();
+
+        migrate(&conn, &berlin).unwrap();
+        let offsets: Vec<i32> = conn
+            .prepare("SELECT utc_offset_s FROM workouts ORDER BY started_at")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(offsets, [BERLIN_WINTER, BERLIN_SUMMER]);
+        let usage_offset: i32 = conn
+            .query_row("SELECT utc_offset_s FROM computer_usage", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(usage_offset, tz::offset_in(&berlin, chrono::Utc::now().timestamp()));
+
+        // Both still count towards the day they were shown on before
+        assert_eq!(stretches_on(&conn, "2024-03-31"), 2);
+        assert_eq!(get_stats_for_date(&conn, "2024-03-31").unwrap().active_s, 60);
+    }
+
+    #[test]
+    fn passwords_logged_in_the_clear_are_hidden() {
+        let conn = open_in_memory();
+        conn.execute(
+            "INSERT INTO settings_history (batch, key, old_value, new_value, source, changed_at, utc_offset_s)
+             VALUES (1, 'mqtt_password', '', 'hunter2', 'window', 0, 0), (1, 'mqtt_user', '', 'me', 'window', 0, 0)",
+            [],
+        )
+        .unwrap();
+        migrate(&conn, &Local).unwrap();
+        let changes = &get_settings_history(&conn, 1).unwrap()[0].changes;
+        let values: Vec<(&str, &str)> = changes.iter().map(|c| (c.old_value.as_str(), c.new_value.as_str())).collect();
+        assert_eq!(values, [("", settings::HIDDEN), ("", "me")]);
+    }
+}
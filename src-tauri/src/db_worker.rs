@@ -0,0 +1,89 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc;
+
+use rusqlite::Connection;
+
+use crate::error::{Error, Result};
+
+type Job = Box<dyn FnOnce(&Connection) + Send>;
+
+/// Owns the database connection on a dedicated thread. Jobs run one at a time
+/// in the order they were queued, so a slow query only ever holds up other
+/// queries, never the timer.
+#[derive(Clone)]
+pub struct DbWorker {
+    jobs: mpsc::Sender<Job>,
+}
+
+fn worker_stopped() -> Error {
+    Error::internal("Database worker is not running")
+}
+
+/// The job was dropped without an answer, which only happens when it panicked.
+fn job_panicked() -> Error {
+    Error::internal("Database job panicked")
+}
+
+impl DbWorker {
+    pub fn spawn(conn: Connection) -> Self {
+        let (jobs, queue) = mpsc::channel::<Job>();
+        std::thread::Builder::new()
+            .name("stretchia-db".to_string())
+            .spawn(move || {
+                for job in queue {
+                    // A panicking job must not take the connection down with it
+                    if panic::catch_unwind(AssertUnwindSafe(|| job(&conn))).is_err() {
+                        log::error!("Database job panicked");
+                    }
+                }
+            })
+            .expect("Failed to start database worker");
+        Self { jobs }
+    }
+
+    /// Queues `f` without waiting for it. Errors are only logged.
+    pub fn execute<E, F>(&self, f: F)
+    where
+        E: Into<Error>,
+        F: FnOnce(&Connection) -> std::result::Result<(), E> + Send + 'static,
+    {
+        let _ = self.jobs.send(Box::new(move |conn| {
+            if let Err(e) = f(conn) {
+                log::error!("Database job failed: {}", e.into());
+            }
+        }));
+    }
+
+    /// Runs `f` on the worker and waits for its result without blocking the caller's thread.
+    /// `f` may fail with any error that converts into ours, e.g. a `rusqlite::Error`.
+    pub async fn call<T, E, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        E: Into<Error>,
+        F: FnOnce(&Connection) -> std::result::Result<T, E> + Send + 'static,
+    {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        self.jobs
+            .send(Box::new(move |conn| {
+                let _ = tx.send(f(conn).map_err(Into::into));
+            }))
+            .map_err(|_| worker_stopped())?;
+        rx.await.map_err(|_| job_panicked())?
+    }
+
+    /// Like `call`, for synchronous contexts such as app setup and shutdown.
+    pub fn call_blocking<T, E, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        E: Into<Error>,
+        F: FnOnce(&Connection) -> std::result::Result<T, E> + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel();
+        self.jobs
+            .send(Box::new(move |conn| {
+                let _ = tx.send(f(conn).map_err(Into::into));
+            }))
+            .map_err(|_| worker_stopped())?;
+        rx.recv().map_err(|_| job_panicked())?
+    }
+}
@@ -0,0 +1,425 @@
+use std::sync::{Arc, Mutex};
+
+use tauri::Manager;
+use zbus::interface;
+use zbus::object_server::SignalEmitter;
+
+use crate::control::{self, Command};
+use crate::error::{self, Error, ErrorCode};
+use crate::timer::{Mode, TimerTickPayload};
+use crate::{lock, SharedState};
+
+// On Linux the timer is exported on the session bus, so desktop scripts can
+// use `busctl`/`gdbus` instead of the HTTP API. Methods go through `control`
+// like every other remote client.
+
+pub const BUS_NAME: &str = "org.stretchia.Timer";
+pub const OBJECT_PATH: &str = "/org/stretchia/Timer";
+
+/// The session bus connection, once there is one.
+pub struct Bus {
+    connection: Mutex<Option<zbus::Connection>>,
+    last: Mutex<Option<Published>>,
+}
+
+/// The properties that signal their changes, as last seen by the tick loop.
+#[derive(Debug, Clone, PartialEq)]
+struct Published {
+    stage: &'static str,
+    mode: String,
+    is_afk: bool,
+}
+
+/// Method errors, as `org.stretchia.Timer.Error.Invalid` etc.
+#[derive(Debug, zbus::DBusError)]
+#[zbus(prefix = "org.stretchia.Timer.Error")]
+enum TimerError {
+    #[zbus(error)]
+    ZBus(zbus::Error),
+    Invalid(String),
+    Conflict(String),
+    Failed(String),
+}
+
+impl From<Error> for TimerError {
+    fn from(e: Error) -> Self {
+        match e.code {
+            ErrorCode::Invalid | ErrorCode::NotFound => TimerError::Invalid(e.message),
+            ErrorCode::Conflict | ErrorCode::Constraint => TimerError::Conflict(e.message),
+            ErrorCode::Busy | ErrorCode::Database | ErrorCode::Io | ErrorCode::Internal => TimerError::Failed(e.message),
+        }
+    }
+}
+
+/// What the interface works on: the running app, or a stand-in in tests.
+trait Backend: Send + Sync + 'static {
+    /// Runs `command`, blocking until it is done.
+    fn execute(&self, command: Command) -> error::Result<()>;
+
+    fn properties(&self) -> Properties;
+}
+
+/// The timer as the interface's properties show it.
+#[derive(Debug, Clone, PartialEq)]
+struct Properties {
+    elapsed_s: u64,
+    stage: &'static str,
+    mode: &'static str,
+    is_afk: bool,
+}
+
+impl Backend for tauri::AppHandle {
+    fn execute(&self, command: Command) -> error::Result<()> {
+        control::execute(self, command).map(|_| ())
+    }
+
+    fn properties(&self) -> Properties {
+        let state = self.state::<SharedState>();
+        let s = lock(&state);
+        Properties {
+            elapsed_s: s.timer.elapsed_s,
+            stage: s.timer.calculate_stage().name(),
+            mode: mode_name(&s.timer.mode),
+            is_afk: s.timer.is_afk,
+        }
+    }
+}
+
+struct Timer {
+    backend: Arc<dyn Backend>,
+}
+
+impl Timer {
+    async fn run(&self, command: Command) -> Result<(), TimerError> {
+        let backend = self.backend.clone();
+        tauri::async_runtime::spawn_blocking(move || backend.execute(command))
+            .await
+            .map_err(|e| TimerError::Failed(e.to_string()))??;
+        Ok(())
+    }
+}
+
+fn mode_name(mode: &Mode) -> &'static str {
+    match mode {
+        Mode::Sitting => "sitting",
+        Mode::Treadmill => "treadmill",
+    }
+}
+
+#[interface(name = "org.stretchia.Timer")]
+impl Timer {
+    async fn stretch(&self) -> Result<(), TimerError> {
+        self.run(Command::Stretch).await
+    }
+
+    async fn skip(&self) -> Result<(), TimerError> {
+        self.run(Command::Skip).await
+    }
+
+    async fn start_treadmill(&self) -> Result<(), TimerError> {
+        self.run(Command::TreadmillStart).await
+    }
+
+    async fn stop_treadmill(&self) -> Result<(), TimerError> {
+        self.run(Command::TreadmillStop).await
+    }
+
+    /// Holds reminders back for `minutes`, or the default 15 if 0.
+    async fn snooze(&self, minutes: u32) -> Result<(), TimerError> {
+        let minutes = (minutes > 0).then_some(minutes as u64);
+        self.run(Command::Snooze { minutes }).await
+    }
+
+    /// Seconds sitting since the last break. Changes every second, so it
+    /// isn't signalled; read it when needed.
+    #[zbus(property(emits_changed_signal = "false"))]
+    fn elapsed_seconds(&self) -> u64 {
+        self.backend.properties().elapsed_s
+    }
+
+    /// `green`, `yellow`, `orange`, `red` or `critical`.
+    #[zbus(property)]
+    fn stage(&self) -> String {
+        self.backend.properties().stage.to_string()
+    }
+
+    /// `sitting` or `treadmill`.
+    #[zbus(property)]
+    fn mode(&self) -> String {
+        self.backend.properties().mode.to_string()
+    }
+
+    #[zbus(property)]
+    fn is_afk(&self) -> bool {
+        self.backend.properties().is_afk
+    }
+
+    /// The timer entered another stage.
+    #[zbus(signal, name = "StageChanged")]
+    async fn stage_transition(
+        emitter: &SignalEmitter<'_>,
+        from: &str,
+        to: &str,
+        elapsed_seconds: u64,
+    ) -> zbus::Result<()>;
+}
+
+/// Claims `org.stretchia.Timer` on the session bus in the background. Without
+/// a session bus the app simply runs without it.
+pub fn start(app: &tauri::AppHandle) {
+    app.manage(Bus {
+        connection: Mutex::new(None),
+        last: Mutex::new(None),
+    });
+    let app = app.clone();
+    tauri::async_runtime::spawn(async move {
+        let backend = Arc::new(app.clone());
+        let connection = async { serve(zbus::connection::Builder::session()?, backend).await };
+        match connection.await {
+            Ok(connection) => {
+                log::info!("Exported {} on the session bus", BUS_NAME);
+                *lock(&app.state::<Bus>().connection) = Some(connection);
+            }
+            Err(e) => log::warn!("Could not register on the session bus: {}", e),
+        }
+    });
+}
+
+/// Connects to the bus `builder` describes, claims `BUS_NAME` and serves the
+/// timer on it.
+async fn serve(builder: zbus::connection::Builder<'_>, backend: Arc<dyn Backend>) -> zbus::Result<zbus::Connection> {
+    builder.name(BUS_NAME)?.serve_at(OBJECT_PATH, Timer { backend })?.build().await
+}
+
+impl Bus {
+    /// Signals the properties in `payload` that changed since the last tick,
+    /// and `StageChanged` if the stage did.
+    pub fn publish(&self, payload: &TimerTickPayload, stage: &'static str) {
+        let Some(connection) = lock(&self.connection).clone() else {
+            return;
+        };
+        let now = Published {
+            stage,
+            mode: payload.mode.to_lowercase(),
+            is_afk: payload.is_afk,
+        };
+        let Some(previous) = lock(&self.last).replace(now.clone()) else {
+            return;
+        };
+        if previous == now {
+            return;
+        }
+        let elapsed_s = payload.elapsed_s;
+        tauri::async_runtime::spawn(async move {
+            if let Err(e) = signal(&connection, &previous, &now, elapsed_s).await {
+                log::warn!("Failed to signal a timer change on D-Bus: {}", e);
+            }
+        });
+    }
+}
+
+async fn signal(connection: &zbus::Connection, previous: &Published, now: &Published, elapsed_s: u64) -> zbus::Result<()> {
+    let iface = connection.object_server().interface::<_, Timer>(OBJECT_PATH).await?;
+    let emitter = iface.signal_emitter();
+    let timer = iface.get().await;
+    if previous.stage != now.stage {
+        timer.stage_changed(emitter).await?;
+        Timer::stage_transition(emitter, previous.stage, now.stage, elapsed_s).await?;
+    }
+    if previous.mode != now.mode {
+        timer.mode_changed(emitter).await?;
+    }
+    if previous.is_afk != now.is_afk {
+        timer.is_afk_changed(emitter).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader};
+    use std::process::{Child, Stdio};
+
+    use futures_util::StreamExt;
+
+    use super::*;
+
+    /// Records the commands it gets and shows fixed properties.
+    #[derive(Default)]
+    struct Fake {
+        commands: Mutex<Vec<String>>,
+    }
+
+    impl Backend for Fake {
+        fn execute(&self, command: Command) -> error::Result<()> {
+            if let Command::TreadmillStop = command {
+                return Err(Error::conflict("Treadmill is not running"));
+            }
+            lock(&self.commands).push(format!("{:?}", command));
+            Ok(())
+        }
+
+        fn properties(&self) -> Properties {
+            Properties {
+                elapsed_s: 1500,
+                stage: "yellow",
+                mode: "sitting",
+                is_afk: false,
+            }
+        }
+    }
+
+    /// A private bus, shut down when dropped.
+    struct TestBus {
+        daemon: Child,
+        address: String,
+    }
+
+    impl TestBus {
+        /// None where `dbus-daemon` isn't installed.
+        fn start() -> Option<TestBus> {
+            let mut daemon = std::process::Command::new("dbus-daemon")
+                .args(["--session", "--nofork", "--print-address=1"])
+                .stdout(Stdio::piped())
+                .spawn()
+                .ok()?;
+            let mut address = String::new();
+            BufReader::new(daemon.stdout.take().unwrap()).read_line(&mut address).unwrap();
+            let address = address.trim().to_string();
+            Some(TestBus { daemon, address })
+        }
+
+        fn builder(&self) -> zbus::connection::Builder<'static> {
+            zbus::connection::Builder::address(self.address.as_str()).unwrap()
+        }
+
+        /// The timer served on the bus, and a client proxy for it.
+        async fn connect(&self, fake: Arc<Fake>) -> (zbus::Connection, zbus::Proxy<'static>) {
+            let server = serve(self.builder(), fake).await.unwrap();
+            let client = self.builder().build().await.unwrap();
+            let proxy = zbus::proxy::Builder::new(&client)
+                .destination(BUS_NAME)
+                .unwrap()
+                .path(OBJECT_PATH)
+                .unwrap()
+                .interface(BUS_NAME)
+                .unwrap()
+                .cache_properties(zbus::proxy::CacheProperties::No)
+                .build()
+                .await
+                .unwrap();
+            (server, proxy)
+        }
+    }
+
+    impl Drop for TestBus {
+        fn drop(&mut self) {
+            let _ = self.daemon.kill();
+            let _ = self.daemon.wait();
+        }
+    }
+
+    fn tick(mode: &str, is_afk: bool) -> TimerTickPayload {
+        TimerTickPayload {
+            mode: mode.to_string(),
+            elapsed_s: 1500,
+            stage: String::new(),
+            is_afk,
+            is_treadmill: mode == "Treadmill",
+            can_undo: false,
+            snoozed_until: None,
+        }
+    }
+
+    /// `StageChanged green→yellow`, or the properties a `PropertiesChanged` names.
+    fn describe(message: &zbus::Message) -> String {
+        let header = message.header();
+        match header.member().unwrap().as_str() {
+            "StageChanged" => {
+                let (from, to, _): (String, String, u64) = message.body().deserialize().unwrap();
+                format!("StageChanged {}→{}", from, to)
+            }
+            "PropertiesChanged" => {
+                let (_, changed, _): (String, std::collections::HashMap<String, zbus::zvariant::OwnedValue>, Vec<String>) =
+                    message.body().deserialize().unwrap();
+                let mut names: Vec<_> = changed.into_keys().collect();
+                names.sort();
+                names.join(",")
+            }
+            other => other.to_string(),
+        }
+    }
+
+    async fn next(signals: &mut zbus::MessageStream) -> String {
+        describe(&signals.next().await.unwrap().unwrap())
+    }
+
+    #[test]
+    fn methods_run_commands() {
+        let Some(bus) = TestBus::start() else {
+            return;
+        };
+        tauri::async_runtime::block_on(async {
+            let fake = Arc::new(Fake::default());
+            let (_server, proxy) = bus.connect(fake.clone()).await;
+            proxy.call_method("Stretch", &()).await.unwrap();
+            proxy.call_method("Snooze", &(20u32,)).await.unwrap();
+            proxy.call_method("Snooze", &(0u32,)).await.unwrap();
+            assert_eq!(
+                *lock(&fake.commands),
+                ["Stretch", "Snooze { minutes: Some(20) }", "Snooze { minutes: None }"]
+            );
+
+            let Err(zbus::Error::MethodError(name, message, _)) = proxy.call_method("StopTreadmill", &()).await else {
+                panic!("StopTreadmill should fail");
+            };
+            assert_eq!(name.as_str(), "org.stretchia.Timer.Error.Conflict");
+            assert_eq!(message.as_deref(), Some("Treadmill is not running"));
+        });
+    }
+
+    #[test]
+    fn properties_read_from_the_backend() {
+        let Some(bus) = TestBus::start() else {
+            return;
+        };
+        tauri::async_runtime::block_on(async {
+            let (_server, proxy) = bus.connect(Arc::new(Fake::default())).await;
+            assert_eq!(proxy.get_property::<String>("Stage").await.unwrap(), "yellow");
+            assert_eq!(proxy.get_property::<String>("Mode").await.unwrap(), "sitting");
+            assert_eq!(proxy.get_property::<u64>("ElapsedSeconds").await.unwrap(), 1500);
+            assert!(!proxy.get_property::<bool>("IsAfk").await.unwrap());
+        });
+    }
+
+    #[test]
+    fn publish_signals_only_what_changed() {
+        let Some(bus) = TestBus::start() else {
+            return;
+        };
+        tauri::async_runtime::block_on(async {
+            let (server, proxy) = bus.connect(Arc::new(Fake::default())).await;
+            let rule = zbus::MatchRule::builder()
+                .msg_type(zbus::message::Type::Signal)
+                .path(OBJECT_PATH)
+                .unwrap()
+                .build();
+            let mut signals = zbus::MessageStream::for_match_rule(rule, proxy.connection(), None).await.unwrap();
+            let published = Bus {
+                connection: Mutex::new(Some(server)),
+                last: Mutex::new(None),
+            };
+
+            // The first tick is only remembered, an unchanged one is dropped
+            published.publish(&tick("Sitting", false), "green");
+            published.publish(&tick("Sitting", false), "green");
+            published.publish(&tick("Sitting", false), "yellow");
+            assert_eq!(next(&mut signals).await, "Stage");
+            assert_eq!(next(&mut signals).await, "StageChanged green→yellow");
+
+            published.publish(&tick("Treadmill", true), "yellow");
+            assert_eq!(next(&mut signals).await, "Mode");
+            assert_eq!(next(&mut signals).await, "IsAfk");
+        });
+    }
+}
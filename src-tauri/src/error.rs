@@ -0,0 +1,116 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::settings::FieldError;
+
+// The error commands return, to the windows as well as to remote clients. It
+// serializes as `{"code": "not-found", "message": "..."}`, plus `fields` when
+// settings were rejected, so callers can branch on the code and show the
+// message.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorCode {
+    /// The request itself is wrong, e.g. a malformed date or invalid settings.
+    Invalid,
+    /// What the request refers to doesn't exist (any more), e.g. a workout
+    /// that was purged from the trash.
+    NotFound,
+    /// Not possible right now, e.g. stopping a treadmill that isn't running.
+    Conflict,
+    /// The database refused a change that breaks one of its constraints.
+    Constraint,
+    /// The database is locked by someone else; trying again may work.
+    Busy,
+    /// Any other database failure.
+    Database,
+    /// A file could not be read or written.
+    Io,
+    /// Something broke on our side, e.g. a database job panicked.
+    Internal,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Error {
+    pub code: ErrorCode,
+    pub message: String,
+    /// The invalid values, when settings were rejected.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<FieldError>,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            fields: Vec::new(),
+        }
+    }
+
+    pub fn invalid(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::Invalid, message)
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::NotFound, message)
+    }
+
+    pub fn conflict(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::Conflict, message)
+    }
+
+    pub fn io(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::Io, message)
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::Internal, message)
+    }
+
+    /// Settings that failed validation, with an error for each field.
+    pub fn invalid_settings(fields: Vec<FieldError>) -> Self {
+        Self {
+            fields,
+            ..Self::invalid("Some settings are invalid")
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Self {
+        let code = match &e {
+            rusqlite::Error::QueryReturnedNoRows => ErrorCode::NotFound,
+            rusqlite::Error::SqliteFailure(failure, _) => match failure.code {
+                rusqlite::ErrorCode::ConstraintViolation => ErrorCode::Constraint,
+                rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked => ErrorCode::Busy,
+                _ => ErrorCode::Database,
+            },
+            _ => ErrorCode::Database,
+        };
+        Self::new(code, e.to_string())
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Self::new(ErrorCode::Io, e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Self::internal(e.to_string())
+    }
+}
@@ -0,0 +1,82 @@
+use std::sync::RwLock;
+
+use rusqlite::Connection;
+use serde::Serialize;
+
+use crate::db::Workout;
+use crate::settings::Settings;
+use crate::{hooks, webhooks};
+
+/// The settings in effect, kept by `configure` so emitting an event doesn't
+/// read them from the database.
+static SETTINGS: RwLock<Option<Settings>> = RwLock::new(None);
+
+/// Something that happened to the timer, passed on to webhooks and hook
+/// commands.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", content = "data")]
+pub enum Event {
+    #[serde(rename = "stage.changed")]
+    StageChanged {
+        from: &'static str,
+        to: &'static str,
+        elapsed_s: u64,
+    },
+    #[serde(rename = "afk.started")]
+    AfkStarted { reason: &'static str, elapsed_s: u64 },
+    #[serde(rename = "afk.ended")]
+    AfkEnded { elapsed_s: u64 },
+    #[serde(rename = "treadmill.started")]
+    TreadmillStarted { sitting_before_s: u64 },
+    /// A stretch, skip or treadmill session was logged.
+    #[serde(rename = "workout.recorded")]
+    WorkoutRecorded(Workout),
+    /// Sent from the settings window to check the webhook setup.
+    #[serde(rename = "ping")]
+    Ping {},
+}
+
+/// An event with the time it happened, as webhooks POST it and hook commands
+/// read it on stdin.
+#[derive(Debug, Clone, Serialize)]
+pub struct Envelope {
+    pub at: i64,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+impl Envelope {
+    /// The event name, e.g. `stage.changed`.
+    pub fn name(&self) -> String {
+        match serde_json::to_value(&self.event) {
+            Ok(value) => value.get("event").and_then(|v| v.as_str()).unwrap_or_default().to_string(),
+            Err(_) => String::new(),
+        }
+    }
+}
+
+/// Makes `settings` the ones events go out with from now on.
+pub fn configure(settings: &Settings) {
+    if let Ok(mut current) = SETTINGS.write() {
+        *current = Some(settings.clone());
+    }
+}
+
+/// Queues webhooks and starts hook commands for `event`. Failures are logged,
+/// never passed on: neither must stand in the way of what triggered them.
+pub fn emit(conn: &Connection, event: Event) {
+    let configured = SETTINGS.read().ok().and_then(|s| s.clone());
+    let settings = match configured.map_or_else(|| Settings::load(conn), Ok) {
+        Ok(settings) => settings,
+        Err(e) => {
+            log::error!("Failed to load settings for an event: {}", e);
+            return;
+        }
+    };
+    let envelope = Envelope {
+        at: chrono::Utc::now().timestamp(),
+        event,
+    };
+    webhooks::enqueue(conn, &settings.webhook_urls, &envelope);
+    hooks::fire(&settings, &envelope);
+}
@@ -0,0 +1,405 @@
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde_json::Value;
+
+use crate::events::{Envelope, Event};
+use crate::settings::Settings;
+use crate::{lock, paths};
+
+// Hook commands are lines of the `hooks` setting, `event: command`, run
+// through the shell when the event happens. They get the event as JSON on
+// stdin and its fields as `STRETCHIA_*` environment variables; what they print
+// goes to `hooks.log` in the data directory.
+
+/// Events a hook can be attached to.
+pub const EVENTS: &[&str] = &[
+    "stage.changed",
+    "stage.green",
+    "stage.yellow",
+    "stage.orange",
+    "stage.red",
+    "stage.critical",
+    "afk.started",
+    "afk.ended",
+    "treadmill.started",
+    "treadmill.stopped",
+    "stretch",
+    "skip",
+    "workout.recorded",
+];
+
+/// Output kept per stream and run.
+const MAX_OUTPUT: usize = 16 * 1024;
+/// Size at which the log is moved to `hooks.log.1` and started over.
+const MAX_LOG_BYTES: u64 = 1024 * 1024;
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Serializes writes to the log across hook threads.
+static LOG_LOCK: Mutex<()> = Mutex::new(());
+
+pub fn log_path() -> PathBuf {
+    paths::data_dir().join("hooks.log")
+}
+
+/// Splits a line of the `hooks` setting into its event and command.
+pub fn parse(line: &str) -> Result<(&str, &str), String> {
+    let Some((event, command)) = line.split_once(':') else {
+        return Err(format!("Expected `event: command`: {}", line));
+    };
+    let (event, command) = (event.trim(), command.trim());
+    if !EVENTS.contains(&event) {
+        return Err(format!("Unknown event: {}", event));
+    }
+    if command.is_empty() {
+        return Err(format!("No command for {}", event));
+    }
+    Ok((event, command))
+}
+
+/// The hook events `event` counts as: the general one and, where there is
+/// one, the specific one.
+fn names(event: &Event) -> Vec<String> {
+    match event {
+        Event::StageChanged { to, .. } => vec!["stage.changed".to_string(), format!("stage.{}", to)],
+        Event::AfkStarted { .. } => vec!["afk.started".to_string()],
+        Event::AfkEnded { .. } => vec!["afk.ended".to_string()],
+        Event::TreadmillStarted { .. } => vec!["treadmill.started".to_string()],
+        Event::WorkoutRecorded(w) => {
+            let specific = match w.workout_type.as_str() {
+                "treadmill" => "treadmill.stopped",
+                other => other,
+            };
+            vec!["workout.recorded".to_string(), specific.to_string()]
+        }
+        Event::Ping {} => Vec::new(),
+    }
+}
+
+/// `STRETCHIA_EVENT`, `STRETCHIA_AT` and one variable per field of the event
+/// data, e.g. `STRETCHIA_ELAPSED_S`.
+fn environment(envelope: &Envelope) -> Vec<(String, String)> {
+    let mut env = vec![
+        ("STRETCHIA_EVENT".to_string(), envelope.name()),
+        ("STRETCHIA_AT".to_string(), envelope.at.to_string()),
+    ];
+    let data = serde_json::to_value(envelope)
+        .ok()
+        .and_then(|mut v| v.get_mut("data").map(Value::take));
+    if let Some(Value::Object(fields)) = data {
+        for (key, value) in fields {
+            let text = match value {
+                Value::String(s) => s,
+                Value::Null => String::new(),
+                other => other.to_string(),
+            };
+            env.push((format!("STRETCHIA_{}", key.to_uppercase()), text));
+        }
+    }
+    env
+}
+
+/// Starts the hooks of `settings` that are attached to the event, each on a
+/// thread of its own.
+pub fn fire(settings: &Settings, envelope: &Envelope) {
+    let names = names(&envelope.event);
+    let hooks: Vec<(String, String)> = settings
+        .hooks
+        .iter()
+        .filter_map(|line| parse(line).ok())
+        .filter(|(event, _)| names.iter().any(|n| n == event))
+        .map(|(event, command)| (event.to_string(), command.to_string()))
+        .collect();
+    if hooks.is_empty() {
+        return;
+    }
+    let input = match serde_json::to_string(envelope) {
+        Ok(input) => input,
+        Err(e) => {
+            log::error!("Failed to serialize event for hooks: {}", e);
+            return;
+        }
+    };
+    let timeout = Duration::from_secs(settings.hook_timeout_s);
+    for (hook, command) in hooks {
+        let mut env = environment(envelope);
+        env.push(("STRETCHIA_HOOK".to_string(), hook.clone()));
+        let input = input.clone();
+        std::thread::spawn(move || {
+            let started = chrono::Local::now();
+            let result = run(&command, &env, &input, timeout);
+            if let Err(e) = &result {
+                log::warn!("Hook `{}` failed: {}", command, e);
+            }
+            write_log(&format_entry(started, &hook, &command, &result));
+        });
+    }
+}
+
+/// How a hook run ended.
+struct Run {
+    status: String,
+    duration: Duration,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+}
+
+fn shell(command: &str) -> Command {
+    #[cfg(windows)]
+    {
+        use std::os::windows::process::CommandExt;
+        const CREATE_NO_WINDOW: u32 = 0x0800_0000;
+        let mut cmd = Command::new("cmd");
+        // cmd has quoting rules of its own; passed as is, the line means what
+        // it would at a prompt
+        cmd.arg("/C").raw_arg(command).creation_flags(CREATE_NO_WINDOW);
+        cmd
+    }
+    #[cfg(not(windows))]
+    {
+        use std::os::unix::process::CommandExt;
+        let mut cmd = Command::new("sh");
+        // A group of its own, so the timeout reaches what the shell started
+        cmd.arg("-c").arg(command).process_group(0);
+        cmd
+    }
+}
+
+/// The shell and everything it started. Killing only the shell would leave
+/// its children running, holding the output pipes and their readers open.
+#[cfg(not(windows))]
+struct Tree(u32);
+
+#[cfg(not(windows))]
+impl Tree {
+    fn new(child: &Child) -> Tree {
+        Tree(child.id())
+    }
+
+    fn kill(&self) {
+        // The shell leads the group (see `shell`) and isn't reaped yet, so
+        // the id can't have been reused
+        unsafe {
+            libc::kill(-(self.0 as libc::pid_t), libc::SIGKILL);
+        }
+    }
+}
+
+/// The shell and everything it started, kept in a job object. Closing the
+/// job leaves its processes alone, so commands may still leave something
+/// running on purpose.
+#[cfg(windows)]
+struct Tree(Option<windows::Win32::Foundation::HANDLE>);
+
+#[cfg(windows)]
+impl Tree {
+    fn new(child: &Child) -> Tree {
+        use std::os::windows::io::AsRawHandle;
+        use windows::core::PCWSTR;
+        use windows::Win32::Foundation::{CloseHandle, HANDLE};
+        use windows::Win32::System::JobObjects::{AssignProcessToJobObject, CreateJobObjectW};
+
+        unsafe {
+            let Ok(job) = CreateJobObjectW(None, PCWSTR::null()) else {
+                return Tree(None);
+            };
+            if AssignProcessToJobObject(job, HANDLE(child.as_raw_handle())).is_err() {
+                let _ = CloseHandle(job);
+                return Tree(None);
+            }
+            Tree(Some(job))
+        }
+    }
+
+    fn kill(&self) {
+        use windows::Win32::System::JobObjects::TerminateJobObject;
+        if let Some(job) = self.0 {
+            unsafe {
+                let _ = TerminateJobObject(job, 1);
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
+impl Drop for Tree {
+    fn drop(&mut self) {
+        if let Some(job) = self.0 {
+            unsafe {
+                let _ = windows::Win32::Foundation::CloseHandle(job);
+            }
+        }
+    }
+}
+
+/// Reads `source` into a shared buffer as it arrives, so whatever was printed
+/// is there even if the command has to be killed.
+fn capture(mut source: impl Read + Send + 'static) -> Arc<Mutex<Vec<u8>>> {
+    let buffer = Arc::new(Mutex::new(Vec::new()));
+    let sink = buffer.clone();
+    std::thread::spawn(move || {
+        let mut chunk = [0u8; 4096];
+        while let Ok(n) = source.read(&mut chunk) {
+            if n == 0 {
+                break;
+            }
+            let mut sink = lock(&sink);
+            let room = MAX_OUTPUT.saturating_sub(sink.len());
+            sink.extend_from_slice(&chunk[..n.min(room)]);
+        }
+    });
+    buffer
+}
+
+fn run(command: &str, env: &[(String, String)], input: &str, timeout: Duration) -> Result<Run, String> {
+    let start = Instant::now();
+    let mut child = shell(command)
+        .envs(env.iter().cloned())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| e.to_string())?;
+    let tree = Tree::new(&child);
+
+    if let Some(mut stdin) = child.stdin.take() {
+        // A command that doesn't read its input closes the pipe early
+        let _ = stdin.write_all(input.as_bytes());
+    }
+    let stdout = child.stdout.take().map(capture);
+    let stderr = child.stderr.take().map(capture);
+
+    let status = loop {
+        if let Some(status) = child.try_wait().map_err(|e| e.to_string())? {
+            break match status.code() {
+                Some(code) => format!("exited with {}", code),
+                None => "killed by a signal".to_string(),
+            };
+        }
+        if start.elapsed() >= timeout {
+            tree.kill();
+            let _ = child.kill();
+            let _ = child.wait();
+            break format!("stopped after the {} s timeout", timeout.as_secs());
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    };
+    // Give the readers a moment to catch the last of the output
+    std::thread::sleep(POLL_INTERVAL);
+    let take = |buffer: Option<Arc<Mutex<Vec<u8>>>>| buffer.map(|b| lock(&b).clone()).unwrap_or_default();
+    Ok(Run {
+        status,
+        duration: start.elapsed(),
+        stdout: take(stdout),
+        stderr: take(stderr),
+    })
+}
+
+fn format_entry(started: chrono::DateTime<chrono::Local>, hook: &str, command: &str, result: &Result<Run, String>) -> String {
+    let mut entry = format!("{} {}: {}\n", started.format("%Y-%m-%d %H:%M:%S"), hook, command);
+    match result {
+        Ok(run) => {
+            entry.push_str(&format!("{} in {:.1} s\n", run.status, run.duration.as_secs_f64()));
+            for (name, output) in [("stdout", &run.stdout), ("stderr", &run.stderr)] {
+                if output.is_empty() {
+                    continue;
+                }
+                entry.push_str(&format!("--- {}\n{}", name, String::from_utf8_lossy(output)));
+                if !output.ends_with(b"\n") {
+                    entry.push('\n');
+                }
+                if output.len() >= MAX_OUTPUT {
+                    entry.push_str("[output cut off]\n");
+                }
+            }
+        }
+        Err(e) => entry.push_str(&format!("could not start: {}\n", e)),
+    }
+    entry.push('\n');
+    entry
+}
+
+fn write_log(entry: &str) {
+    let _guard = lock(&LOG_LOCK);
+    let path = log_path();
+    if std::fs::metadata(&path).is_ok_and(|m| m.len() > MAX_LOG_BYTES) {
+        let _ = std::fs::rename(&path, path.with_extension("log.1"));
+    }
+    let result = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|mut file| file.write_all(entry.as_bytes()));
+    if let Err(e) = result {
+        log::warn!("Failed to write {}: {}", path.display(), e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn envelope(event: Event) -> Envelope {
+        Envelope { at: 1_700_000_000, event }
+    }
+
+    fn var<'a>(env: &'a [(String, String)], name: &str) -> Option<&'a str> {
+        env.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+
+    #[test]
+    fn parse_splits_at_the_first_colon() {
+        assert_eq!(parse("stretch: notify-send hi"), Ok(("stretch", "notify-send hi")));
+        assert_eq!(parse("  stage.red :curl http://x/y  "), Ok(("stage.red", "curl http://x/y")));
+        assert_eq!(parse("skip: echo a:b"), Ok(("skip", "echo a:b")));
+    }
+
+    #[test]
+    fn parse_rejects_malformed_lines() {
+        assert!(parse("stretch notify-send").unwrap_err().starts_with("Expected"));
+        assert_eq!(parse("stage.blue: echo"), Err("Unknown event: stage.blue".to_string()));
+        assert_eq!(parse("stretch:   "), Err("No command for stretch".to_string()));
+    }
+
+    #[test]
+    fn environment_has_the_event_and_its_fields() {
+        let env = environment(&envelope(Event::StageChanged {
+            from: "green",
+            to: "yellow",
+            elapsed_s: 1500,
+        }));
+        assert_eq!(var(&env, "STRETCHIA_EVENT"), Some("stage.changed"));
+        assert_eq!(var(&env, "STRETCHIA_AT"), Some("1700000000"));
+        assert_eq!(var(&env, "STRETCHIA_FROM"), Some("green"));
+        assert_eq!(var(&env, "STRETCHIA_TO"), Some("yellow"));
+        assert_eq!(var(&env, "STRETCHIA_ELAPSED_S"), Some("1500"));
+        assert_eq!(env.len(), 5);
+    }
+
+    #[test]
+    fn environment_of_an_event_without_fields() {
+        let env = environment(&envelope(Event::Ping {}));
+        assert_eq!(var(&env, "STRETCHIA_EVENT"), Some("ping"));
+        assert_eq!(env.len(), 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn timeout_kills_what_the_shell_started() {
+        let pid_file = std::env::temp_dir().join(format!("stretchia-hook-{}", std::process::id()));
+        let command = format!("sleep 30 & echo $! > '{}'; wait", pid_file.display());
+        let run = run(&command, &[], "", Duration::from_secs(1)).unwrap();
+        assert_eq!(run.status, "stopped after the 1 s timeout");
+
+        let pid = std::fs::read_to_string(&pid_file).unwrap();
+        let _ = std::fs::remove_file(&pid_file);
+        // Gone, or a zombie waiting for init to reap it
+        let ps = Command::new("ps").args(["-o", "stat=", "-p", pid.trim()]).output().unwrap();
+        let state = String::from_utf8_lossy(&ps.stdout);
+        assert!(state.trim().is_empty() || state.starts_with('Z'), "sleep {} outlived the hook", pid.trim());
+    }
+}
@@ -0,0 +1,111 @@
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+
+use interprocess::local_socket::prelude::*;
+use interprocess::local_socket::{GenericFilePath, GenericNamespaced, Listener, ListenerOptions, Name, Stream};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::control::{self, Command};
+use crate::error::Error;
+use crate::paths;
+
+// The running app listens on a local socket (a named pipe on Windows) for
+// `Command`s from the command line. Each connection carries one request and
+// one reply, each a line of JSON.
+
+/// Answer to a `Command`.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Reply {
+    Ok(Value),
+    Error(Error),
+}
+
+/// `stretchia.sock` in the data directory, so copies with different data
+/// directories (e.g. a portable one) don't talk to each other.
+fn socket_path() -> PathBuf {
+    paths::data_dir().join("stretchia.sock")
+}
+
+fn name() -> io::Result<Name<'static>> {
+    if cfg!(windows) {
+        // Pipe names are global; tell data directories apart by a hash
+        use std::hash::{Hash, Hasher};
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        paths::data_dir().hash(&mut hasher);
+        format!("stretchia-{:016x}", hasher.finish()).to_ns_name::<GenericNamespaced>()
+    } else {
+        socket_path().to_fs_name::<GenericFilePath>()
+    }
+}
+
+/// Claims the socket. Fails while another instance holds it, which is how a
+/// second launch notices the first.
+pub fn bind() -> io::Result<Listener> {
+    if let Some(dir) = socket_path().parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    match ListenerOptions::new().name(name()?).create_sync() {
+        Err(e) if e.kind() == io::ErrorKind::AddrInUse && !cfg!(windows) => {
+            // Someone is answering: leave their socket alone
+            if Stream::connect(name()?).is_ok() {
+                return Err(e);
+            }
+            // Left behind by an instance that crashed
+            std::fs::remove_file(socket_path())?;
+            ListenerOptions::new().name(name()?).create_sync()
+        }
+        result => result,
+    }
+}
+
+/// Starts answering commands on `listener` in the background.
+pub fn serve(app: tauri::AppHandle, listener: Listener) {
+    std::thread::spawn(move || {
+        for conn in listener.incoming() {
+            match conn {
+                Ok(stream) => {
+                    let app = app.clone();
+                    std::thread::spawn(move || {
+                        if let Err(e) = handle(&app, stream) {
+                            log::warn!("Command-line request failed: {}", e);
+                        }
+                    });
+                }
+                Err(e) => log::warn!("Command-line connection failed: {}", e),
+            }
+        }
+    });
+}
+
+fn handle(app: &tauri::AppHandle, stream: Stream) -> io::Result<()> {
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    // A launch checking whether we're alive connects without asking anything
+    if line.trim().is_empty() {
+        return Ok(());
+    }
+    let reply = match serde_json::from_str::<Command>(&line) {
+        Ok(command) => match control::execute(app, command) {
+            Ok(value) => Reply::Ok(value),
+            Err(e) => Reply::Error(e),
+        },
+        Err(e) => Reply::Error(Error::invalid(format!("Invalid request: {}", e))),
+    };
+    let mut text = serde_json::to_string(&reply)?;
+    text.push('\n');
+    (&stream).write_all(text.as_bytes())
+}
+
+/// Sends `command` to the running app and waits for its reply. Fails if no
+/// instance is running.
+pub fn send(command: &Command) -> io::Result<Reply> {
+    let stream = Stream::connect(name()?)?;
+    let mut text = serde_json::to_string(command)?;
+    text.push('\n');
+    (&stream).write_all(text.as_bytes())?;
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    Ok(serde_json::from_str(&line)?)
+}
@@ -1,17 +1,69 @@
+mod actions;
 mod afk;
+mod api;
+pub mod cli;
+mod control;
 mod db;
+mod db_worker;
+#[cfg(target_os = "linux")]
+mod dbus;
+mod error;
+mod events;
+mod hooks;
+mod ipc;
+mod metrics;
+mod mqtt;
+pub mod paths;
+mod presence;
+mod settings;
+mod settings_file;
 mod timer;
 mod tray;
-
-use std::sync::Mutex;
+mod tz;
+mod usage;
+pub mod webhooks;
+
+use std::sync::{Arc, Mutex, MutexGuard};
+use afk::AfkReason;
+use db_worker::DbWorker;
+use error::Error;
+use events::Event;
 use rusqlite::Connection;
+use settings::Settings;
 use tauri::{Emitter, Listener, Manager};
 use timer::{Mode, TimerState, TimerTickPayload};
 
+/// Timer-side state. Database access goes through the `DbWorker`; the lock is
+/// only held across a query when the timer has to change together with it.
 pub struct AppState {
     pub timer: TimerState,
-    pub db: Connection,
     last_stage: Option<timer::Stage>,
+    undo_stack: Vec<actions::UndoEntry>,
+}
+
+pub type SharedState = Arc<Mutex<AppState>>;
+
+/// Locks `mutex`, recovering from poisoning: a panic in one command must not
+/// stop the timer for the rest of the session.
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| {
+        log::warn!("Recovering from a poisoned lock");
+        mutex.clear_poison();
+        poisoned.into_inner()
+    })
+}
+
+fn tick_payload(s: &AppState) -> TimerTickPayload {
+    let stage = s.timer.calculate_stage();
+    TimerTickPayload {
+        mode: format!("{:?}", s.timer.mode),
+        elapsed_s: s.timer.elapsed_s,
+        stage: stage.as_str().to_string(),
+        is_afk: s.timer.is_afk,
+        is_treadmill: s.timer.mode == Mode::Treadmill,
+        can_undo: actions::can_undo(s),
+        snoozed_until: s.timer.snoozed_until.filter(|_| s.timer.is_snoozed()),
+    }
 }
 
 fn open_settings_window(app: &tauri::AppHandle) {
@@ -31,131 +83,418 @@ fn open_stats_window(app: &tauri::AppHandle) {
 }
 
 #[tauri::command]
-fn cmd_get_timer_state(state: tauri::State<'_, Mutex<AppState>>) -> Result<TimerTickPayload, String> {
-    let s = state.lock().map_err(|e| e.to_string())?;
-    let stage = s.timer.calculate_stage();
-    Ok(TimerTickPayload {
-        mode: format!("{:?}", s.timer.mode),
-        elapsed_s: s.timer.elapsed_s,
-        stage: stage.as_str().to_string(),
-        is_afk: s.timer.is_afk,
-        is_treadmill: s.timer.mode == Mode::Treadmill,
-    })
+fn cmd_get_timer_state(state: tauri::State<'_, SharedState>) -> Result<TimerTickPayload, Error> {
+    Ok(tick_payload(&lock(&state)))
 }
 
 #[tauri::command]
-fn cmd_record_stretch(state: tauri::State<'_, Mutex<AppState>>) -> Result<(), String> {
-    let mut s = state.lock().map_err(|e| e.to_string())?;
-    let sitting_before = s.timer.elapsed_s as i64;
-    db::record_stretch(&s.db, sitting_before).map_err(|e| e.to_string())?;
-    s.timer.reset();
+async fn cmd_record_stretch(
+    worker: tauri::State<'_, DbWorker>,
+    state: tauri::State<'_, SharedState>,
+) -> Result<(), Error> {
+    actions::stretch(&worker, &state).await
+}
+
+#[tauri::command]
+fn cmd_start_treadmill(
+    worker: tauri::State<'_, DbWorker>,
+    state: tauri::State<'_, SharedState>,
+) -> Result<(), Error> {
+    actions::start_treadmill(&worker, &state);
     Ok(())
 }
 
 #[tauri::command]
-fn cmd_start_treadmill(state: tauri::State<'_, Mutex<AppState>>) -> Result<(), String> {
-    let mut s = state.lock().map_err(|e| e.to_string())?;
-    s.timer.start_treadmill();
+async fn cmd_stop_treadmill(
+    worker: tauri::State<'_, DbWorker>,
+    state: tauri::State<'_, SharedState>,
+) -> Result<(), Error> {
+    actions::stop_treadmill(&worker, &state).await
+}
+
+#[tauri::command]
+fn cmd_snooze(state: tauri::State<'_, SharedState>, minutes: u64) -> Result<(), Error> {
+    actions::snooze(&state, minutes)
+}
+
+#[tauri::command]
+async fn cmd_get_today_history(worker: tauri::State<'_, DbWorker>) -> Result<Vec<db::Workout>, Error> {
+    worker.call(db::get_today_workouts).await
+}
+
+#[tauri::command]
+async fn cmd_get_settings(worker: tauri::State<'_, DbWorker>) -> Result<Settings, Error> {
+    worker.call(Settings::load).await
+}
+
+#[tauri::command]
+fn cmd_get_settings_schema() -> Vec<settings::SchemaField> {
+    settings::schema()
+}
+
+/// Keys currently set by the settings file, which the settings window can't change.
+#[tauri::command]
+fn cmd_get_settings_overrides() -> Vec<String> {
+    settings_file::overlay()
+        .keys()
+        .filter(|key| Settings::is_setting(key))
+        .cloned()
+        .collect()
+}
+
+/// Puts the settings in effect to use: the running timer picks them up, the
+/// HTTP API and MQTT publisher follow their switches, events go out with them
+/// and the windows are told to reload theirs.
+fn apply_settings(app: &tauri::AppHandle, settings: &Settings) {
+    lock(&app.state::<SharedState>()).timer.apply_settings(settings);
+    app.state::<api::Api>().configure(app, settings);
+    app.state::<mqtt::Mqtt>().configure(app, settings);
+    events::configure(settings);
+    let _ = app.emit("settings-changed", ());
+}
+
+/// Validates and stores `settings`, then applies them to the running timer.
+/// Invalid values are rejected as a whole, with an error for each field. Keys
+/// set in the settings file keep their stored values.
+#[tauri::command]
+async fn cmd_save_settings(
+    app: tauri::AppHandle,
+    worker: tauri::State<'_, DbWorker>,
+    settings: Settings,
+) -> Result<(), Error> {
+    settings.validate().map_err(Error::invalid_settings)?;
+    let effective = worker
+        .call(move |conn| {
+            settings.save_under_file(conn, &settings_file::overlay(), "window")?;
+            Settings::load(conn)
+        })
+        .await?;
+    apply_settings(&app, &effective);
     Ok(())
 }
 
+/// Writes the shareable settings in effect to the settings file and returns its path.
 #[tauri::command]
-fn cmd_stop_treadmill(state: tauri::State<'_, Mutex<AppState>>) -> Result<(), String> {
-    let mut s = state.lock().map_err(|e| e.to_string())?;
-    let (started, duration, sitting_before) = s.timer.stop_treadmill();
-    db::record_treadmill(&s.db, started, duration as i64, sitting_before as i64)
-        .map_err(|e| e.to_string())?;
+async fn cmd_export_settings(worker: tauri::State<'_, DbWorker>) -> Result<String, Error> {
+    let settings = worker.call(Settings::load).await?;
+    let path = settings_file::export(&settings)?;
+    Ok(path.display().to_string())
+}
+
+/// Stores the values from the settings file as the saved settings, so they
+/// stay when the file is removed.
+#[tauri::command]
+async fn cmd_import_settings(app: tauri::AppHandle, worker: tauri::State<'_, DbWorker>) -> Result<(), Error> {
+    let values = settings_file::read()?;
+    let effective = worker
+        .call(move |conn| -> Result<Settings, Error> {
+            let mut settings = Settings::load_stored(conn)?;
+            settings
+                .merge(&values)
+                .and_then(|_| settings.validate())
+                .map_err(Error::invalid_settings)?;
+            settings.save(conn, "import")?;
+            Ok(Settings::load(conn)?)
+        })
+        .await?;
+    apply_settings(&app, &effective);
     Ok(())
 }
 
+/// Recent settings changes, newest first.
 #[tauri::command]
-fn cmd_get_today_history(state: tauri::State<'_, Mutex<AppState>>) -> Result<Vec<db::Workout>, String> {
-    let s = state.lock().map_err(|e| e.to_string())?;
-    db::get_today_workouts(&s.db).map_err(|e| e.to_string())
+async fn cmd_get_settings_history(worker: tauri::State<'_, DbWorker>) -> Result<Vec<db::SettingsChangeSet>, Error> {
+    worker
+        .call(|conn| db::get_settings_history(conn, 50))
+        .await
+}
+
+/// Restores the saved settings to what they were right after history batch
+/// `batch`. The rollback is itself recorded, so it can be undone the same way.
+#[tauri::command]
+async fn cmd_rollback_settings(
+    app: tauri::AppHandle,
+    worker: tauri::State<'_, DbWorker>,
+    batch: i64,
+) -> Result<(), Error> {
+    let effective = worker
+        .call(move |conn| -> Result<Settings, Error> {
+            let settings = Settings::at_batch(conn, batch)?;
+            settings.validate().map_err(Error::invalid_settings)?;
+            settings.save(conn, "rollback")?;
+            Ok(Settings::load(conn)?)
+        })
+        .await?;
+    apply_settings(&app, &effective);
+    Ok(())
 }
 
+/// Recent webhook deliveries, newest first.
 #[tauri::command]
-fn cmd_get_settings(state: tauri::State<'_, Mutex<AppState>>) -> Result<Vec<db::Setting>, String> {
-    let s = state.lock().map_err(|e| e.to_string())?;
-    db::load_settings(&s.db).map_err(|e| e.to_string())
+async fn cmd_get_webhook_deliveries(worker: tauri::State<'_, DbWorker>) -> Result<Vec<db::WebhookDelivery>, Error> {
+    worker
+        .call(|conn| db::get_webhook_deliveries(conn, 20))
+        .await
 }
 
+/// Queues a `ping` event for every webhook URL.
 #[tauri::command]
-fn cmd_update_setting(state: tauri::State<'_, Mutex<AppState>>, key: String, value: String) -> Result<(), String> {
-    let s = state.lock().map_err(|e| e.to_string())?;
-    db::update_setting(&s.db, &key, &value).map_err(|e| e.to_string())
+async fn cmd_test_webhooks(worker: tauri::State<'_, DbWorker>) -> Result<(), Error> {
+    worker
+        .call(|conn| -> Result<(), Error> {
+            events::emit(conn, Event::Ping {});
+            Ok(())
+        })
+        .await
 }
 
 #[tauri::command]
-fn cmd_open_settings(app: tauri::AppHandle) -> Result<(), String> {
+fn cmd_open_settings(app: tauri::AppHandle) -> Result<(), Error> {
     open_settings_window(&app);
     Ok(())
 }
 
 #[tauri::command]
-fn cmd_open_stats(app: tauri::AppHandle) -> Result<(), String> {
+fn cmd_open_stats(app: tauri::AppHandle) -> Result<(), Error> {
     open_stats_window(&app);
     Ok(())
 }
 
 #[tauri::command]
-fn cmd_delete_workout(state: tauri::State<'_, Mutex<AppState>>, id: i64) -> Result<(), String> {
-    let s = state.lock().map_err(|e| e.to_string())?;
-    db::delete_workout(&s.db, id).map_err(|e| e.to_string())
+async fn cmd_delete_workout(
+    worker: tauri::State<'_, DbWorker>,
+    state: tauri::State<'_, SharedState>,
+    id: i64,
+) -> Result<(), Error> {
+    let state = state.inner().clone();
+    worker
+        .call(move |conn| {
+            db::delete_workout(conn, id)?;
+            cap_open_streak(conn, &mut lock(&state))
+        })
+        .await
+}
+
+fn validate_workout(workout_type: &str, started_at: i64, ended_at: i64) -> Result<(), Error> {
+    if !["stretch", "treadmill", "skip"].contains(&workout_type) {
+        return Err(Error::invalid(format!("Unknown workout type: {}", workout_type)));
+    }
+    if ended_at < started_at {
+        return Err(Error::invalid("A workout cannot end before it starts"));
+    }
+    if started_at > chrono::Utc::now().timestamp() {
+        return Err(Error::invalid("A workout cannot start in the future"));
+    }
+    Ok(())
+}
+
+/// The running timer is the open sitting streak after the latest workout, so
+/// it has to shrink when history is edited or back-filled behind it.
+fn cap_open_streak(conn: &Connection, s: &mut AppState) -> Result<(), Error> {
+    let Some(latest) = db::get_latest_workout(conn)? else {
+        return Ok(());
+    };
+    let reset_at = db::sitting_reset_at(&latest);
+    match s.timer.mode {
+        Mode::Sitting => {
+            let max = (chrono::Utc::now().timestamp() - reset_at).max(0) as u64;
+            s.timer.elapsed_s = s.timer.elapsed_s.min(max);
+        }
+        Mode::Treadmill => {
+            let started = s.timer.treadmill_start.unwrap_or(reset_at);
+            let max = (started - reset_at).max(0) as u64;
+            s.timer.sitting_before_s = s.timer.sitting_before_s.min(max);
+        }
+    }
+    Ok(())
 }
 
 #[tauri::command]
-fn cmd_get_day_stats(state: tauri::State<'_, Mutex<AppState>>, date: String) -> Result<db::DayStats, String> {
-    let s = state.lock().map_err(|e| e.to_string())?;
-    db::get_stats_for_date(&s.db, &date).map_err(|e| e.to_string())
+async fn cmd_update_workout(
+    worker: tauri::State<'_, DbWorker>,
+    state: tauri::State<'_, SharedState>,
+    id: i64,
+    workout_type: String,
+    started_at: i64,
+    ended_at: i64,
+    sitting_before_s: i64,
+) -> Result<(), Error> {
+    validate_workout(&workout_type, started_at, ended_at)?;
+    let state = state.inner().clone();
+    worker
+        .call(move |conn| {
+            db::update_workout(conn, id, &workout_type, started_at, ended_at, sitting_before_s.max(0))?;
+            cap_open_streak(conn, &mut lock(&state))
+        })
+        .await
 }
 
 #[tauri::command]
-fn cmd_quit(app: tauri::AppHandle) -> Result<(), String> {
+async fn cmd_add_workout(
+    worker: tauri::State<'_, DbWorker>,
+    state: tauri::State<'_, SharedState>,
+    workout_type: String,
+    started_at: i64,
+    ended_at: i64,
+    sitting_before_s: Option<i64>,
+) -> Result<i64, Error> {
+    validate_workout(&workout_type, started_at, ended_at)?;
+    let state = state.inner().clone();
+    worker
+        .call(move |conn| -> Result<i64, Error> {
+            let mut s = lock(&state);
+            let sitting_before_s = match sitting_before_s {
+                Some(v) => v.max(0),
+                None => {
+                    let (open_s, open_end) = match s.timer.mode {
+                        Mode::Sitting => (s.timer.elapsed_s as i64, chrono::Utc::now().timestamp()),
+                        Mode::Treadmill => (
+                            s.timer.sitting_before_s as i64,
+                            s.timer.treadmill_start.unwrap_or_else(|| chrono::Utc::now().timestamp()),
+                        ),
+                    };
+                    db::estimate_sitting_before(conn, started_at, open_s, open_end)?
+                }
+            };
+            let id = db::insert_workout(conn, &workout_type, started_at, ended_at, sitting_before_s)?;
+            cap_open_streak(conn, &mut s)?;
+            Ok(id)
+        })
+        .await
+}
+
+#[tauri::command]
+async fn cmd_undo_workout_change(
+    worker: tauri::State<'_, DbWorker>,
+    state: tauri::State<'_, SharedState>,
+) -> Result<Option<String>, Error> {
+    let state = state.inner().clone();
+    worker
+        .call(move |conn| -> Result<Option<String>, Error> {
+            let action = db::undo_last_change(conn)?;
+            cap_open_streak(conn, &mut lock(&state))?;
+            Ok(action)
+        })
+        .await
+}
+
+#[tauri::command]
+async fn cmd_get_trash(worker: tauri::State<'_, DbWorker>) -> Result<Vec<db::TrashedWorkout>, Error> {
+    worker
+        .call(|conn| {
+            purge_trash(conn);
+            db::get_deleted_workouts(conn)
+        })
+        .await
+}
+
+#[tauri::command]
+async fn cmd_restore_workout(
+    worker: tauri::State<'_, DbWorker>,
+    state: tauri::State<'_, SharedState>,
+    id: i64,
+) -> Result<(), Error> {
+    let state = state.inner().clone();
+    worker
+        .call(move |conn| {
+            db::restore_workout(conn, id)?;
+            cap_open_streak(conn, &mut lock(&state))
+        })
+        .await
+}
+
+/// How often a running app empties expired workouts out of the trash.
+const TRASH_PURGE_INTERVAL_S: i64 = 3600;
+
+fn purge_trash(conn: &Connection) {
+    let retention_days = Settings::load(conn).unwrap_or_default().trash_retention_days;
+    let _ = db::purge_trash(conn, retention_days as i64);
+}
+
+#[tauri::command]
+async fn cmd_get_day_stats(worker: tauri::State<'_, DbWorker>, date: String) -> Result<db::DayStats, Error> {
+    worker
+        .call(move |conn| db::get_stats_for_date(conn, &date))
+        .await
+}
+
+#[tauri::command]
+async fn cmd_get_day_sessions(worker: tauri::State<'_, DbWorker>, date: String) -> Result<Vec<db::Session>, Error> {
+    worker
+        .call(move |conn| db::get_sessions_for_date(conn, &date))
+        .await
+}
+
+/// Settings changes made on `date`, to mark on the charts.
+#[tauri::command]
+async fn cmd_get_day_settings_changes(
+    worker: tauri::State<'_, DbWorker>,
+    date: String,
+) -> Result<Vec<db::SettingsChangeSet>, Error> {
+    worker
+        .call(move |conn| db::get_settings_history_for_date(conn, &date))
+        .await
+}
+
+#[tauri::command]
+async fn cmd_get_hourly_usage(
+    worker: tauri::State<'_, DbWorker>,
+    from: String,
+    to: String,
+) -> Result<Vec<db::HourlyUsage>, Error> {
+    worker
+        .call(move |conn| db::get_hourly_usage(conn, &from, &to))
+        .await
+}
+
+#[tauri::command]
+async fn cmd_get_hour_of_day_usage(
+    worker: tauri::State<'_, DbWorker>,
+    from: String,
+    to: String,
+) -> Result<Vec<db::HourOfDayUsage>, Error> {
+    worker
+        .call(move |conn| db::get_hour_of_day_usage(conn, &from, &to))
+        .await
+}
+
+#[tauri::command]
+fn cmd_quit(app: tauri::AppHandle) -> Result<(), Error> {
     app.exit(0);
     Ok(())
 }
 
 #[tauri::command]
-fn cmd_record_skip(state: tauri::State<'_, Mutex<AppState>>) -> Result<(), String> {
-    let mut s = state.lock().map_err(|e| e.to_string())?;
-    let sitting_before = s.timer.elapsed_s as i64;
-    db::record_skip(&s.db, sitting_before).map_err(|e| e.to_string())?;
-    s.timer.reset();
-    Ok(())
+async fn cmd_record_skip(
+    worker: tauri::State<'_, DbWorker>,
+    state: tauri::State<'_, SharedState>,
+) -> Result<(), Error> {
+    actions::skip(&worker, &state).await
 }
 
 #[tauri::command]
-fn cmd_apply_settings(app: tauri::AppHandle, state: tauri::State<'_, Mutex<AppState>>) -> Result<(), String> {
-    let mut s = state.lock().map_err(|e| e.to_string())?;
-    let afk_threshold = db::get_setting(&s.db, "afk_threshold_min")
-        .and_then(|v| v.parse::<u64>().ok())
-        .unwrap_or(5)
-        * 60;
-    s.timer.afk_threshold_s = afk_threshold;
-    s.timer.warn_at_min = db::get_setting(&s.db, "warn_at_min")
-        .and_then(|v| v.parse::<u64>().ok())
-        .unwrap_or(30);
-    s.timer.shake_at_min = db::get_setting(&s.db, "shake_at_min")
-        .and_then(|v| v.parse::<u64>().ok())
-        .unwrap_or(60);
-    drop(s);
-    let _ = app.emit("settings-changed", ());
-    Ok(())
+async fn cmd_undo_timer_action(
+    worker: tauri::State<'_, DbWorker>,
+    state: tauri::State<'_, SharedState>,
+) -> Result<bool, Error> {
+    actions::undo(&worker, &state).await
 }
 
 #[tauri::command]
-fn cmd_save_window_position(state: tauri::State<'_, Mutex<AppState>>, x: i32, y: i32) -> Result<(), String> {
-    let s = state.lock().map_err(|e| e.to_string())?;
-    db::update_setting(&s.db, "window_x", &x.to_string()).map_err(|e| e.to_string())?;
-    db::update_setting(&s.db, "window_y", &y.to_string()).map_err(|e| e.to_string())?;
-    Ok(())
+async fn cmd_save_window_position(worker: tauri::State<'_, DbWorker>, x: i32, y: i32) -> Result<(), Error> {
+    worker
+        .call(move |conn| {
+            db::update_setting(conn, "window_x", &x.to_string())?;
+            db::update_setting(conn, "window_y", &y.to_string())
+        })
+        .await
 }
 
-fn restore_window_position(app: &tauri::AppHandle, db: &Connection) {
-    let x = db::get_setting(db, "window_x");
-    let y = db::get_setting(db, "window_y");
-    if let (Some(x_str), Some(y_str)) = (x, y) {
+fn restore_window_position(app: &tauri::AppHandle, worker: &DbWorker) {
+    let saved = worker.call_blocking(|conn| -> Result<_, Error> {
+        Ok((db::get_setting(conn, "window_x"), db::get_setting(conn, "window_y")))
+    });
+    if let Ok((Some(x_str), Some(y_str))) = saved {
         if let (Ok(x), Ok(y)) = (x_str.parse::<i32>(), y_str.parse::<i32>()) {
             if let Some(w) = app.get_webview_window("main") {
                 let _ = w.set_position(tauri::Position::Physical(tauri::PhysicalPosition { x, y }));
@@ -166,116 +505,219 @@ fn restore_window_position(app: &tauri::AppHandle, db: &Connection) {
 
 fn start_tick_loop(app: tauri::AppHandle) {
     std::thread::spawn(move || {
+        let state = app.state::<SharedState>().inner().clone();
+        let worker = app.state::<DbWorker>().inner().clone();
+        let accounting = app.state::<Arc<Mutex<usage::Accounting>>>().inner().clone();
+        let mut last_tick = chrono::Utc::now().timestamp();
+        let mut last_purge = last_tick;
         loop {
             std::thread::sleep(std::time::Duration::from_secs(1));
 
-            let state = app.state::<Mutex<AppState>>();
-            let mut s = match state.lock() {
-                Ok(s) => s,
-                Err(_) => continue,
-            };
-
-            // Check AFK
+            // Query idle state before taking the lock; on macOS it spawns a process
+            let now = chrono::Local::now();
             let idle = afk::get_idle_seconds();
-            let was_afk = s.timer.is_afk;
-            s.timer.is_afk = idle >= s.timer.afk_threshold_s;
+            let locked = afk::is_session_locked();
 
-            if s.timer.is_afk {
-                let _ = db::update_computer_usage(&s.db, 0, 1);
+            let mut s = lock(&state);
+
+            // Check AFK
+            let afk_reason = if locked {
+                Some(AfkReason::Lock)
+            } else if idle >= s.timer.afk_threshold_s {
+                Some(AfkReason::Idle)
             } else {
+                None
+            };
+            let was_afk = s.timer.is_afk;
+            s.timer.is_afk = afk_reason.is_some();
+            if !s.timer.is_afk {
+                let day = db::day_of(now.timestamp(), now.offset().local_minus_utc(), s.timer.day_start_s);
+                s.timer.roll_over(&day);
                 s.timer.elapsed_s += 1;
-                let _ = db::update_computer_usage(&s.db, 1, 0);
             }
 
             // Update tray icon on stage change
             let stage = s.timer.calculate_stage();
             let stage_changed = s.last_stage.as_ref() != Some(&stage);
+            let mut events = Vec::new();
+            if stage_changed {
+                if let Some(from) = &s.last_stage {
+                    events.push(Event::StageChanged {
+                        from: from.name(),
+                        to: stage.name(),
+                        elapsed_s: s.timer.elapsed_s,
+                    });
+                }
+            }
+            match (was_afk, afk_reason) {
+                (false, Some(reason)) => events.push(Event::AfkStarted {
+                    reason: reason.as_str(),
+                    elapsed_s: s.timer.elapsed_s,
+                }),
+                (true, None) => events.push(Event::AfkEnded {
+                    elapsed_s: s.timer.elapsed_s,
+                }),
+                _ => {}
+            }
             if stage_changed || was_afk != s.timer.is_afk {
                 s.last_stage = Some(stage.clone());
                 tray::update_tray_icon(app.app_handle(), &stage);
             }
 
-            let payload = TimerTickPayload {
-                mode: format!("{:?}", s.timer.mode),
-                elapsed_s: s.timer.elapsed_s,
-                stage: stage.as_str().to_string(),
-                is_afk: s.timer.is_afk,
-                is_treadmill: s.timer.mode == Mode::Treadmill,
-            };
+            let payload = tick_payload(&s);
+            let day_start_s = s.timer.day_start_s;
 
             drop(s);
+            app.state::<mqtt::Mqtt>().publish(&payload, stage.name());
+            #[cfg(target_os = "linux")]
+            app.state::<dbus::Bus>().publish(&payload, stage.name());
             let _ = app.emit("timer-tick", payload);
+
+            // Usage counters and the presence log are kept on the database worker
+            let accounting = accounting.clone();
+            let previous_tick = last_tick;
+            worker.execute(move |conn| {
+                for event in events {
+                    events::emit(conn, event);
+                }
+                lock(&accounting).tick(conn, afk_reason, idle, previous_tick, now, day_start_s)
+            });
+            last_tick = now.timestamp();
+
+            if last_tick - last_purge >= TRASH_PURGE_INTERVAL_S {
+                last_purge = last_tick;
+                worker.execute(|conn| -> Result<(), Error> {
+                    purge_trash(conn);
+                    Ok(())
+                });
+            }
         }
     });
 }
 
+/// Takes the command socket, or returns `None` after showing the copy that
+/// holds it. A socket that neither can be taken nor answers may belong to a
+/// copy that is just exiting, so that gets one more try; after that this copy
+/// exits rather than run without the lock.
+fn claim_instance() -> Option<interprocess::local_socket::Listener> {
+    let show = control::Command::Show { window: None };
+    match ipc::bind() {
+        Ok(listener) => return Some(listener),
+        Err(_) if ipc::send(&show).is_ok() => return None,
+        Err(_) => std::thread::sleep(std::time::Duration::from_secs(1)),
+    }
+    match ipc::bind() {
+        Ok(listener) => Some(listener),
+        Err(_) if ipc::send(&show).is_ok() => None,
+        Err(e) => {
+            cli::attach_console();
+            eprintln!(
+                "Stretchia is already running or its command socket in {} is unusable: {}",
+                paths::data_dir().display(),
+                e
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
-    let conn = db::initialize().expect("Failed to initialize database");
-
-    let afk_threshold = db::get_setting(&conn, "afk_threshold_min")
-        .and_then(|v| v.parse::<u64>().ok())
-        .unwrap_or(5)
-        * 60;
+    // Only one copy may run: a second tick loop would count usage twice. The
+    // command socket doubles as the lock; a second launch brings the running
+    // copy to the front instead.
+    let Some(listener) = claim_instance() else {
+        return;
+    };
 
-    let warn_at = db::get_setting(&conn, "warn_at_min")
-        .and_then(|v| v.parse::<u64>().ok())
-        .unwrap_or(30);
-    let shake_at = db::get_setting(&conn, "shake_at_min")
-        .and_then(|v| v.parse::<u64>().ok())
-        .unwrap_or(60);
+    let conn = db::initialize().expect("Failed to initialize database");
+    purge_trash(&conn);
+    let _ = db::purge_webhook_deliveries(&conn, webhooks::LOG_RETENTION_DAYS);
 
-    let mut timer_state = TimerState::new();
-    timer_state.afk_threshold_s = afk_threshold;
-    timer_state.warn_at_min = warn_at;
-    timer_state.shake_at_min = shake_at;
+    let settings = Settings::load(&conn).unwrap_or_default();
+    events::configure(&settings);
+    let timer_state = TimerState::new(&settings);
 
     let app_state = AppState {
         timer: timer_state,
-        db: conn,
         last_stage: None,
+        undo_stack: Vec::new(),
     };
 
     tauri::Builder::default()
-        .manage(Mutex::new(app_state))
+        .manage(Arc::new(Mutex::new(app_state)) as SharedState)
+        .manage(DbWorker::spawn(conn))
+        .manage(Arc::new(Mutex::new(usage::Accounting::new())))
+        .manage(api::Api::new())
+        .manage(mqtt::Mqtt::new())
         .invoke_handler(tauri::generate_handler![
             cmd_get_timer_state,
             cmd_record_stretch,
             cmd_start_treadmill,
             cmd_stop_treadmill,
+            cmd_snooze,
             cmd_get_today_history,
             cmd_get_settings,
-            cmd_update_setting,
+            cmd_get_settings_schema,
+            cmd_save_settings,
+            cmd_get_settings_overrides,
+            cmd_export_settings,
+            cmd_import_settings,
+            cmd_get_settings_history,
+            cmd_rollback_settings,
+            cmd_get_webhook_deliveries,
+            cmd_test_webhooks,
             cmd_save_window_position,
             cmd_open_settings,
             cmd_open_stats,
             cmd_delete_workout,
+            cmd_update_workout,
+            cmd_add_workout,
+            cmd_undo_workout_change,
+            cmd_get_trash,
+            cmd_restore_workout,
             cmd_get_day_stats,
-            cmd_apply_settings,
+            cmd_get_day_sessions,
+            cmd_get_day_settings_changes,
+            cmd_get_hourly_usage,
+            cmd_get_hour_of_day_usage,
             cmd_record_skip,
+            cmd_undo_timer_action,
             cmd_quit,
         ])
-        .setup(|app| {
+        .setup(move |app| {
             tray::create_tray(app.handle())?;
+            app.state::<api::Api>().configure(app.handle(), &settings);
+            app.state::<mqtt::Mqtt>().configure(app.handle(), &settings);
+            ipc::serve(app.handle().clone(), listener);
 
             // Restore window position
-            {
-                let state = app.state::<Mutex<AppState>>();
-                let s = state.lock().unwrap();
-                restore_window_position(app.handle(), &s.db);
-            }
+            restore_window_position(app.handle(), &app.state::<DbWorker>());
 
             // Handle tray "Stretch Now" event
             let handle = app.handle().clone();
             app.listen("tray-stretch", move |_| {
-                let state = handle.state::<Mutex<AppState>>();
-                let mut s = match state.lock() {
-                    Ok(s) => s,
-                    Err(_) => return,
-                };
-                let sitting_before = s.timer.elapsed_s as i64;
-                let _ = db::record_stretch(&s.db, sitting_before);
-                s.timer.reset();
+                let handle = handle.clone();
+                tauri::async_runtime::spawn(async move {
+                    let worker = handle.state::<DbWorker>().inner().clone();
+                    let state = handle.state::<SharedState>().inner().clone();
+                    if actions::stretch(&worker, &state).await.is_ok() {
+                        let _ = handle.emit("history-changed", ());
+                    }
+                });
+            });
+
+            // Handle tray "Undo" event
+            let handle_undo = app.handle().clone();
+            app.listen("tray-undo", move |_| {
+                let handle = handle_undo.clone();
+                tauri::async_runtime::spawn(async move {
+                    let worker = handle.state::<DbWorker>().inner().clone();
+                    let state = handle.state::<SharedState>().inner().clone();
+                    if let Ok(true) = actions::undo(&worker, &state).await {
+                        let _ = handle.emit("history-changed", ());
+                    }
+                });
             });
 
             // Handle tray "Statistics" event
@@ -290,6 +732,20 @@ pub fn run() {
                 open_settings_window(&handle3);
             });
 
+            // Apply edits to the settings file while running
+            let handle_file = app.handle().clone();
+            settings_file::watch(move || {
+                let worker = handle_file.state::<DbWorker>();
+                match worker.call_blocking(Settings::load) {
+                    Ok(settings) => apply_settings(&handle_file, &settings),
+                    Err(e) => log::error!("Failed to reload settings: {}", e),
+                }
+            });
+
+            webhooks::start(app.state::<DbWorker>().inner().clone());
+            #[cfg(target_os = "linux")]
+            dbus::start(app.handle());
+
             // Start the timer tick loop
             start_tick_loop(app.handle().clone());
 
@@ -300,9 +756,18 @@ pub fn run() {
                         .build(),
                 )?;
             }
+            paths::log_legacy_move();
 
             Ok(())
         })
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application")
+        .run(|app, event| {
+            if let tauri::RunEvent::Exit = event {
+                let accounting = app.state::<Arc<Mutex<usage::Accounting>>>().inner().clone();
+                let _ = app
+                    .state::<DbWorker>()
+                    .call_blocking(move |conn| lock(&accounting).flush(conn));
+            }
+        });
 }
@@ -2,5 +2,9 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
 fn main() {
+  // `stretchia status` and friends talk to the running app instead
+  if let Some(code) = app_lib::cli::main() {
+    std::process::exit(code);
+  }
   app_lib::run();
 }
@@ -0,0 +1,72 @@
+use std::fmt::{Display, Write};
+use std::sync::{Arc, Mutex};
+
+use tauri::Manager;
+
+use crate::db::{self, Totals};
+use crate::db_worker::DbWorker;
+use crate::timer::{Mode, TimerState};
+use crate::{lock, usage, SharedState};
+
+// Served as `/metrics` by the HTTP API, in the Prometheus text format. Gauges
+// come from the running timer and the workout history, which shrinks when
+// entries are deleted or undone; counters only from usage, which never does.
+
+/// Gathers the current values and renders them.
+pub fn gather(app: &tauri::AppHandle) -> Result<String, String> {
+    let accounting = app.state::<Arc<Mutex<usage::Accounting>>>().inner().clone();
+    // Usage is written in batches; add what is still buffered. Both are read on
+    // the worker, where the buffer is flushed, so nothing counts twice.
+    let totals = app
+        .state::<DbWorker>()
+        .call_blocking(move |conn| -> rusqlite::Result<Totals> {
+            let mut totals = db::get_totals(conn)?;
+            let (active_s, afk_s) = lock(&accounting).unwritten_usage();
+            totals.active_s += active_s;
+            totals.afk_s += afk_s;
+            Ok(totals)
+        })
+        .map_err(|e| e.to_string())?;
+    let state = app.state::<SharedState>();
+    let s = lock(&state);
+    Ok(render(&s.timer, &totals, chrono::Utc::now().timestamp()))
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, value: impl Display) {
+    let _ = writeln!(out, "# HELP stretchia_{} {}", name, help);
+    let _ = writeln!(out, "# TYPE stretchia_{} {}", name, kind);
+    let _ = writeln!(out, "stretchia_{} {}", name, value);
+}
+
+fn render(timer: &TimerState, totals: &Totals, now: i64) -> String {
+    // The running treadmill session counts as it goes, so the value doesn't
+    // jump when it is logged
+    let walking_s = match (&timer.mode, timer.treadmill_start) {
+        (Mode::Treadmill, Some(start)) => (now - start).max(0),
+        _ => 0,
+    };
+
+    let mut out = String::new();
+    metric(&mut out, "elapsed_seconds", "gauge", "Seconds sitting since the last break.", timer.elapsed_s);
+    metric(
+        &mut out,
+        "stage",
+        "gauge",
+        "Reminder stage: 0 green, 1 yellow, 2 orange, 3 red, 4 critical.",
+        timer.calculate_stage().level(),
+    );
+    metric(&mut out, "afk", "gauge", "1 while away from the computer.", timer.is_afk as u8);
+    metric(&mut out, "treadmill", "gauge", "1 during a treadmill session.", (timer.mode == Mode::Treadmill) as u8);
+    metric(&mut out, "stretches", "gauge", "Stretches in the history.", totals.stretch_count);
+    metric(&mut out, "skips", "gauge", "Skipped breaks in the history.", totals.skip_count);
+    metric(
+        &mut out,
+        "treadmill_seconds",
+        "gauge",
+        "Seconds walked on the treadmill, as in the history.",
+        totals.treadmill_s + walking_s,
+    );
+    metric(&mut out, "active_seconds_total", "counter", "Seconds at the computer.", totals.active_s);
+    metric(&mut out, "afk_seconds_total", "counter", "Seconds away from the computer.", totals.afk_s);
+    out
+}
@@ -0,0 +1,419 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+
+use rumqttc::{Client, Connection, Event, LastWill, MqttOptions, Packet, QoS};
+use serde_json::{json, Value};
+
+use crate::control::{self, Command};
+use crate::lock;
+use crate::paths;
+use crate::settings::Settings;
+use crate::timer::TimerTickPayload;
+
+// State is published as retained topics under the configured prefix, e.g.
+// `stretchia/stage`, so a subscriber gets the current value straight away.
+// While the broker is away the latest value of each topic is kept and sent
+// again on reconnect; older ones wouldn't be worth delivering.
+
+/// Where Home Assistant looks for discovery messages.
+const DISCOVERY_PREFIX: &str = "homeassistant";
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+/// Requests the client holds before `try_publish` starts refusing them.
+const QUEUE_CAP: usize = 64;
+/// Longest wait between reconnect attempts.
+const MAX_RETRY_S: u64 = 60;
+/// Elapsed time is republished this often while nothing else changes.
+const ELAPSED_EVERY_S: u64 = 60;
+
+/// The opt-in MQTT publisher.
+pub struct Mqtt {
+    running: Mutex<Option<Running>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Config {
+    host: String,
+    port: u16,
+    username: String,
+    password: String,
+    prefix: String,
+}
+
+impl Config {
+    fn from_settings(settings: &Settings) -> Option<Config> {
+        settings.mqtt_enabled.then(|| Config {
+            host: settings.mqtt_host.trim().to_string(),
+            port: settings.mqtt_port,
+            username: settings.mqtt_username.clone(),
+            password: settings.mqtt_password.clone(),
+            prefix: settings.mqtt_topic_prefix.clone(),
+        })
+    }
+
+    fn topic(&self, name: &str) -> String {
+        format!("{}/{}", self.prefix, name)
+    }
+
+    /// The prefix as an id, so two machines publishing under different
+    /// prefixes show up as two devices.
+    fn node_id(&self) -> String {
+        self.prefix
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' })
+            .collect()
+    }
+}
+
+struct Running {
+    client: Client,
+    shared: Arc<Shared>,
+}
+
+/// What the connection thread and `publish` both need.
+struct Shared {
+    config: Config,
+    stop: AtomicBool,
+    connected: AtomicBool,
+    /// Last payload of each state topic.
+    retained: Mutex<BTreeMap<String, String>>,
+    last_elapsed_s: Mutex<Option<u64>>,
+}
+
+impl Mqtt {
+    pub fn new() -> Self {
+        Self {
+            running: Mutex::new(None),
+        }
+    }
+
+    /// Connects, disconnects or reconnects to match `settings`.
+    pub fn configure(&self, app: &tauri::AppHandle, settings: &Settings) {
+        let mut running = lock(&self.running);
+        let wanted = Config::from_settings(settings);
+        if running.as_ref().map(|r| &r.shared.config) == wanted.as_ref() {
+            return;
+        }
+        if let Some(old) = running.take() {
+            old.shared.stop.store(true, Ordering::SeqCst);
+            // A clean disconnect doesn't trigger the last will, so say it ourselves
+            let _ = old
+                .client
+                .try_publish(old.shared.config.topic("availability"), QoS::AtLeastOnce, true, "offline");
+            let _ = old.client.try_disconnect();
+            log::info!("Disconnected from MQTT broker {}:{}", old.shared.config.host, old.shared.config.port);
+        }
+        if let Some(config) = wanted {
+            *running = Some(start(app.clone(), config));
+        }
+    }
+
+    /// Publishes the parts of `payload` that changed. Called every tick.
+    pub fn publish(&self, payload: &TimerTickPayload, stage: &str) {
+        let running = lock(&self.running);
+        let Some(running) = running.as_ref() else {
+            return;
+        };
+        let shared = &running.shared;
+
+        let mut values = vec![
+            ("stage", stage.to_string()),
+            ("mode", payload.mode.to_lowercase()),
+            ("afk", if payload.is_afk { "ON" } else { "OFF" }.to_string()),
+        ];
+        {
+            let mut last = lock(&shared.last_elapsed_s);
+            let due = match *last {
+                None => true,
+                // Went back: the timer was reset
+                Some(last) => payload.elapsed_s < last || payload.elapsed_s - last >= ELAPSED_EVERY_S,
+            };
+            if due {
+                *last = Some(payload.elapsed_s);
+                values.push(("elapsed_s", payload.elapsed_s.to_string()));
+            }
+        }
+
+        let mut retained = lock(&shared.retained);
+        for (name, value) in values {
+            let topic = shared.config.topic(name);
+            if retained.get(&topic) == Some(&value) {
+                continue;
+            }
+            if shared.connected.load(Ordering::SeqCst) {
+                if let Err(e) = running.client.try_publish(topic.as_str(), QoS::AtLeastOnce, true, value.as_str()) {
+                    // Left unrecorded, so the next tick tries again
+                    log::warn!("Failed to publish {}: {}", topic, e);
+                    continue;
+                }
+            }
+            retained.insert(topic, value);
+        }
+    }
+}
+
+/// `stretchia-` and a random suffix kept in `mqtt-client-id` in the data
+/// directory. Brokers drop the older of two connections with the same id, so
+/// two installs must never share one, while each keeps its own across restarts.
+fn client_id() -> String {
+    let path = paths::data_dir().join("mqtt-client-id");
+    if let Ok(id) = std::fs::read_to_string(&path) {
+        let id = id.trim();
+        if !id.is_empty() {
+            return id.to_string();
+        }
+    }
+    // Short enough for brokers that only take the 23 characters MQTT 3.1.1 promises
+    let mut bytes = [0u8; 6];
+    if let Err(e) = getrandom::fill(&mut bytes) {
+        log::warn!("Could not generate an MQTT client id: {}", e);
+    }
+    let id = format!("stretchia-{}", bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>());
+    if let Err(e) = std::fs::write(&path, &id) {
+        log::warn!("Could not write {}: {}", path.display(), e);
+    }
+    id
+}
+
+fn start(app: tauri::AppHandle, config: Config) -> Running {
+    let mut options = MqttOptions::new(client_id(), config.host.as_str(), config.port);
+    options.set_keep_alive(KEEP_ALIVE);
+    options.set_last_will(LastWill::new(config.topic("availability"), "offline", QoS::AtLeastOnce, true));
+    if !config.username.is_empty() {
+        options.set_credentials(config.username.as_str(), config.password.as_str());
+    }
+    let (client, connection) = Client::new(options, QUEUE_CAP);
+    let shared = Arc::new(Shared {
+        config,
+        stop: AtomicBool::new(false),
+        connected: AtomicBool::new(false),
+        retained: Mutex::new(BTreeMap::new()),
+        last_elapsed_s: Mutex::new(None),
+    });
+
+    let thread_client = client.clone();
+    let thread_shared = shared.clone();
+    std::thread::spawn(move || run(app, thread_client, connection, thread_shared));
+    Running { client, shared }
+}
+
+/// Drives the connection until the publisher is stopped. Iterating is what
+/// reconnects, so on errors this only waits, a little longer each time.
+fn run(app: tauri::AppHandle, client: Client, mut connection: Connection, shared: Arc<Shared>) {
+    // Commands wait on the database; one thread runs them in order of arrival
+    // while this one keeps the connection moving. It ends along with `commands`.
+    let (commands, queue) = mpsc::channel::<String>();
+    std::thread::spawn(move || {
+        for text in queue {
+            run_command(&app, &text);
+        }
+    });
+
+    let config = &shared.config;
+    let command_topic = config.topic("command");
+    let mut retry_s = 1;
+    for event in connection.iter() {
+        if shared.stop.load(Ordering::SeqCst) {
+            break;
+        }
+        match event {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                log::info!("Connected to MQTT broker {}:{}", config.host, config.port);
+                retry_s = 1;
+                shared.connected.store(true, Ordering::SeqCst);
+                announce(&client, &shared, &command_topic);
+            }
+            Ok(Event::Incoming(Packet::Publish(message))) if message.topic == command_topic => {
+                let _ = commands.send(String::from_utf8_lossy(&message.payload).into_owned());
+            }
+            Ok(_) => {}
+            Err(e) => {
+                if shared.connected.swap(false, Ordering::SeqCst) {
+                    log::warn!("Lost MQTT broker {}:{}: {}", config.host, config.port, e);
+                } else if retry_s == 1 {
+                    log::warn!("Could not reach MQTT broker {}:{}: {}", config.host, config.port, e);
+                }
+                std::thread::sleep(Duration::from_secs(retry_s));
+                retry_s = (retry_s * 2).min(MAX_RETRY_S);
+                if shared.stop.load(Ordering::SeqCst) {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Subscribes and (re)sends everything retained, after every connect.
+fn announce(client: &Client, shared: &Shared, command_topic: &str) {
+    let mut messages = vec![(shared.config.topic("availability"), "online".to_string())];
+    messages.extend(discovery(&shared.config).into_iter().map(|(topic, config)| (topic, config.to_string())));
+    messages.extend(lock(&shared.retained).iter().map(|(topic, value)| (topic.clone(), value.clone())));
+
+    if let Err(e) = client.try_subscribe(command_topic, QoS::AtLeastOnce) {
+        log::warn!("Failed to subscribe to {}: {}", command_topic, e);
+    }
+    for (topic, payload) in messages {
+        if let Err(e) = client.try_publish(topic.as_str(), QoS::AtLeastOnce, true, payload) {
+            log::warn!("Failed to publish {}: {}", topic, e);
+        }
+    }
+}
+
+/// Home Assistant discovery messages: a sensor per state topic and a button
+/// per action, all on one device.
+fn discovery(config: &Config) -> Vec<(String, Value)> {
+    let node = config.node_id();
+    let device = json!({
+        "identifiers": [format!("stretchia_{}", node)],
+        "name": "Stretchia",
+        "manufacturer": "Stretchia",
+        "sw_version": env!("CARGO_PKG_VERSION"),
+    });
+    let entity = |component: &str, object: &str, mut fields: Value| {
+        fields["unique_id"] = json!(format!("stretchia_{}_{}", node, object));
+        fields["availability_topic"] = json!(config.topic("availability"));
+        fields["device"] = device.clone();
+        let topic = format!("{}/{}/stretchia_{}/{}/config", DISCOVERY_PREFIX, component, node, object);
+        (topic, fields)
+    };
+    let button = |object: &str, name: &str, payload: &str, icon: &str| {
+        entity(
+            "button",
+            object,
+            json!({ "name": name, "command_topic": config.topic("command"), "payload_press": payload, "icon": icon }),
+        )
+    };
+
+    vec![
+        entity(
+            "sensor",
+            "stage",
+            json!({ "name": "Stage", "state_topic": config.topic("stage"), "icon": "mdi:human-handsup" }),
+        ),
+        entity(
+            "sensor",
+            "elapsed",
+            json!({
+                "name": "Sitting time",
+                "state_topic": config.topic("elapsed_s"),
+                "unit_of_measurement": "s",
+                "device_class": "duration",
+                "state_class": "measurement",
+            }),
+        ),
+        entity(
+            "sensor",
+            "mode",
+            json!({ "name": "Mode", "state_topic": config.topic("mode"), "icon": "mdi:walk" }),
+        ),
+        entity(
+            "binary_sensor",
+            "afk",
+            json!({ "name": "Away", "state_topic": config.topic("afk"), "payload_on": "ON", "payload_off": "OFF" }),
+        ),
+        button("stretch", "Stretch", "stretch", "mdi:human-handsup"),
+        button("skip", "Skip break", "skip", "mdi:debug-step-over"),
+        button("treadmill_start", "Start treadmill", "treadmill start", "mdi:walk"),
+        button("treadmill_stop", "Stop treadmill", "treadmill stop", "mdi:seat"),
+        button("snooze", "Snooze", "snooze", "mdi:alarm-snooze"),
+    ]
+}
+
+/// Parses a message from the command topic: the same words as on the command
+/// line, e.g. `stretch` or `snooze 30`. Only actions are accepted, as there is
+/// nobody to answer a query to.
+fn parse_command(text: &str) -> Result<Command, String> {
+    let words: Vec<&str> = text.split_whitespace().collect();
+    match Command::from_words(&words)? {
+        command @ (Command::Stretch
+        | Command::Skip
+        | Command::TreadmillStart
+        | Command::TreadmillStop
+        | Command::TreadmillToggle
+        | Command::Snooze { .. }
+        | Command::Undo) => Ok(command),
+        _ => Err(format!("Not an action: {}", text)),
+    }
+}
+
+fn run_command(app: &tauri::AppHandle, text: &str) {
+    let command = match parse_command(text) {
+        Ok(command) => command,
+        Err(e) => {
+            log::warn!("MQTT command ignored: {}", e);
+            return;
+        }
+    };
+    if let Err(e) = control::execute(app, command) {
+        log::warn!("MQTT command {} failed: {}", text, e.message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+
+    fn config() -> Config {
+        Config {
+            host: "localhost".to_string(),
+            port: 1883,
+            username: String::new(),
+            password: String::new(),
+            prefix: "desk/Stretchia".to_string(),
+        }
+    }
+
+    #[test]
+    fn only_actions_are_run() {
+        let parsed = |text: &str| parse_command(text).map(|c| format!("{:?}", c));
+        assert_eq!(parsed("stretch").unwrap(), "Stretch");
+        assert_eq!(parsed(" treadmill  start\n").unwrap(), "TreadmillStart");
+        assert_eq!(parsed("snooze 30").unwrap(), "Snooze { minutes: Some(30) }");
+        assert_eq!(parsed("status").unwrap_err(), "Not an action: status");
+        assert!(parsed("show settings").is_err());
+        assert!(parsed("snooze soon").is_err());
+        assert!(parsed("").is_err());
+    }
+
+    #[test]
+    fn discovery_describes_one_device() {
+        let config = config();
+        let messages = discovery(&config);
+
+        let (topic, stage) = &messages[0];
+        assert_eq!(topic, "homeassistant/sensor/stretchia_desk_stretchia/stage/config");
+        assert_eq!(stage["state_topic"], "desk/Stretchia/stage");
+        assert_eq!(stage["unique_id"], "stretchia_desk_stretchia_stage");
+
+        let ids: BTreeSet<_> = messages.iter().map(|(_, m)| m["unique_id"].as_str().unwrap()).collect();
+        assert_eq!(ids.len(), messages.len());
+        for (_, message) in &messages {
+            assert_eq!(message["availability_topic"], "desk/Stretchia/availability");
+            assert_eq!(message["device"]["identifiers"][0], "stretchia_desk_stretchia");
+        }
+
+        // Sensors read what `publish` writes
+        let states: BTreeSet<_> = messages.iter().filter_map(|(_, m)| m["state_topic"].as_str()).collect();
+        let published: BTreeSet<_> = ["stage", "mode", "afk", "elapsed_s"].map(|name| config.topic(name)).into();
+        assert_eq!(states, published.iter().map(String::as_str).collect());
+    }
+
+    #[test]
+    fn discovery_buttons_send_commands() {
+        let config = config();
+        let buttons: Vec<_> = discovery(&config)
+            .into_iter()
+            .filter(|(topic, _)| topic.starts_with("homeassistant/button/"))
+            .map(|(_, m)| m)
+            .collect();
+        assert_eq!(buttons.len(), 5);
+        for button in buttons {
+            assert_eq!(button["command_topic"], "desk/Stretchia/command");
+            let payload = button["payload_press"].as_str().unwrap();
+            assert!(parse_command(payload).is_ok(), "{} is not an action", payload);
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use crate::error::{self, Error};
+
+const APP_DIR: &str = "Stretchia";
+/// Environment variable that overrides where data is kept.
+pub const DATA_DIR_ENV: &str = "STRETCHIA_DATA_DIR";
+/// A file with this name next to the executable turns on portable mode, for
+/// copies started without command-line flags (e.g. from a USB stick).
+const PORTABLE_MARKER: &str = "portable";
+/// Files that make up the database, WAL and shared memory included.
+const DB_FILES: [&str; 3] = ["data.db", "data.db-wal", "data.db-shm"];
+
+static DATA_DIR: OnceLock<PathBuf> = OnceLock::new();
+/// What moving legacy data did: the directory it came from, or why it failed.
+/// Kept until the logger is up, as the data directory is resolved before it.
+static LEGACY_MOVE: OnceLock<Result<PathBuf, String>> = OnceLock::new();
+
+/// Directory holding the database. Resolved once, in order of precedence:
+/// `--data-dir <path>`, portable mode (`--portable` or a `portable` file next
+/// to the executable), `STRETCHIA_DATA_DIR`, then the platform's data
+/// directory: `$XDG_DATA_HOME` (`~/.local/share`) on Linux,
+/// `~/Library/Application Support` on macOS and `%APPDATA%` on Windows.
+pub fn data_dir() -> &'static Path {
+    DATA_DIR.get_or_init(|| {
+        let args: Vec<String> = std::env::args().skip(1).collect();
+        resolve(&args)
+    })
+}
+
+fn resolve(args: &[String]) -> PathBuf {
+    if let Some(dir) = flag_value(args, "--data-dir") {
+        return PathBuf::from(dir);
+    }
+    if args.iter().any(|a| a == "--portable") || exe_dir().join(PORTABLE_MARKER).exists() {
+        return exe_dir().join("data");
+    }
+    if let Some(dir) = std::env::var_os(DATA_DIR_ENV).filter(|v| !v.is_empty()) {
+        return PathBuf::from(dir);
+    }
+    let dir = dirs::data_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join(APP_DIR);
+    if let Some(outcome) = migrate_legacy(&dir) {
+        let _ = LEGACY_MOVE.set(outcome);
+    }
+    dir
+}
+
+/// Logs whether data was moved out of the legacy directory on startup.
+pub fn log_legacy_move() {
+    match LEGACY_MOVE.get() {
+        Some(Ok(legacy)) => log::info!("Moved data from {} to {}", legacy.display(), data_dir().display()),
+        Some(Err(e)) => log::error!("{}", e),
+        None => {}
+    }
+}
+
+/// Value of `--name <value>` or `--name=<value>`.
+fn flag_value<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if arg == name {
+            return iter.next().map(String::as_str);
+        }
+        if let Some(value) = arg.strip_prefix(name).and_then(|rest| rest.strip_prefix('=')) {
+            return Some(value);
+        }
+    }
+    None
+}
+
+fn exe_dir() -> PathBuf {
+    std::env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(Path::to_path_buf))
+        .unwrap_or_else(|| PathBuf::from("."))
+}
+
+/// Earlier versions kept data in `$HOME/Stretchia` everywhere but Windows.
+/// Moves it to `dir` unless `dir` already has a database. Files are copied
+/// first and the old ones only removed once all copies succeeded, so a failed
+/// move never leaves a database split from its WAL. None if there was nothing
+/// to move.
+fn migrate_legacy(dir: &Path) -> Option<Result<PathBuf, String>> {
+    if cfg!(target_os = "windows") {
+        return None;
+    }
+    let legacy = dirs::home_dir()?.join(APP_DIR);
+    if legacy == dir || !legacy.join(DB_FILES[0]).exists() || dir.join(DB_FILES[0]).exists() {
+        return None;
+    }
+    if let Err(e) = std::fs::create_dir_all(dir) {
+        return Some(Err(format!("Could not create {}: {}", dir.display(), e)));
+    }
+
+    let present: Vec<&str> = DB_FILES.into_iter().filter(|name| legacy.join(name).exists()).collect();
+    for name in &present {
+        if let Err(e) = std::fs::copy(legacy.join(name), dir.join(name)) {
+            for name in &present {
+                let _ = std::fs::remove_file(dir.join(name));
+            }
+            return Some(Err(format!("Could not move {} to {}: {}", legacy.display(), dir.display(), e)));
+        }
+    }
+    for name in &present {
+        let _ = std::fs::remove_file(legacy.join(name));
+    }
+    // Only succeeds if nothing else was kept there
+    let _ = std::fs::remove_dir(&legacy);
+    Some(Ok(legacy))
+}
+
+/// Content of the secret kept in `path`, e.g. the API token. A random one is
+/// written on first use, readable only by the current user.
+pub fn secret(path: &Path) -> error::Result<String> {
+    if let Ok(secret) = std::fs::read_to_string(path) {
+        let secret = secret.trim();
+        if !secret.is_empty() {
+            return Ok(secret.to_string());
+        }
+    }
+    let mut bytes = [0u8; 32];
+    getrandom::fill(&mut bytes).map_err(|e| Error::internal(format!("Could not generate a secret: {}", e)))?;
+    let secret: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    write_private(path, &secret).map_err(|e| Error::io(format!("Could not write {}: {}", path.display(), e)))?;
+    Ok(secret)
+}
+
+fn write_private(path: &Path, content: &str) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    std::io::Write::write_all(&mut options.open(path)?, content.as_bytes())
+}
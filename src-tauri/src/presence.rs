@@ -0,0 +1,62 @@
+use rusqlite::Connection;
+
+use crate::afk::AfkReason;
+use crate::db;
+
+/// A tick arriving this much later than expected means the machine was asleep.
+pub const SUSPEND_GAP_S: i64 = 15;
+
+/// The session currently being written to the `sessions` table.
+pub struct Presence {
+    id: i64,
+    started_at: i64,
+    ended_at: i64,
+    afk: Option<AfkReason>,
+}
+
+/// Extends the open session up to `now`, or closes it and opens a new one
+/// when the user's state changed. `since` is when the new state began: an idle
+/// AFK period starts at the last input, not when the threshold was crossed.
+/// Extensions stay in memory until `flush`; only transitions hit the database.
+pub fn track(
+    conn: &Connection,
+    current: &mut Option<Presence>,
+    afk: Option<AfkReason>,
+    since: i64,
+    now: i64,
+) -> rusqlite::Result<()> {
+    if let Some(p) = current.as_mut() {
+        if p.afk == afk {
+            p.ended_at = now;
+            return Ok(());
+        }
+    }
+
+    let start = match current.as_ref() {
+        // The suspend gap is exact; don't let a backdated idle period eat into it
+        Some(p) if p.afk == Some(AfkReason::Suspend) => now,
+        Some(p) => since.clamp(p.started_at, now),
+        None => now,
+    };
+    if let Some(p) = current.as_ref() {
+        db::extend_session(conn, p.id, start)?;
+    }
+
+    let kind = if afk.is_some() { "afk" } else { "active" };
+    let id = db::start_session(conn, kind, afk.map(|r| r.as_str()), start, now)?;
+    *current = Some(Presence {
+        id,
+        started_at: start,
+        ended_at: now,
+        afk,
+    });
+    Ok(())
+}
+
+/// Writes the open session's current end time.
+pub fn flush(conn: &Connection, current: &Option<Presence>) -> rusqlite::Result<()> {
+    match current {
+        Some(p) => db::extend_session(conn, p.id, p.ended_at),
+        None => Ok(()),
+    }
+}
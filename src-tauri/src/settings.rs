@@ -0,0 +1,539 @@
+use std::collections::HashMap;
+
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{db, hooks, settings_file};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WindowAnchor {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+/// User settings. Stored as key/value rows in the `settings` table; missing or
+/// unreadable values fall back to the defaults below, which are the only ones.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub afk_threshold_min: u64,
+    pub warn_at_min: u64,
+    pub shake_at_min: u64,
+    pub window_opacity: f64,
+    pub history_dots_count: u32,
+    pub window_anchor: WindowAnchor,
+    pub trash_retention_days: u32,
+    pub day_start_hour: u32,
+    pub reset_timer_on_new_day: bool,
+    pub api_enabled: bool,
+    pub api_port: u16,
+    pub api_metrics: bool,
+    pub webhook_urls: Vec<String>,
+    pub hooks: Vec<String>,
+    pub hook_timeout_s: u64,
+    pub mqtt_enabled: bool,
+    pub mqtt_host: String,
+    pub mqtt_port: u16,
+    pub mqtt_username: String,
+    pub mqtt_password: String,
+    pub mqtt_topic_prefix: String,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            afk_threshold_min: 5,
+            warn_at_min: 45,
+            shake_at_min: 75,
+            window_opacity: 0.8,
+            history_dots_count: 10,
+            window_anchor: WindowAnchor::TopRight,
+            trash_retention_days: 30,
+            day_start_hour: 0,
+            reset_timer_on_new_day: false,
+            api_enabled: false,
+            api_port: 7411,
+            api_metrics: false,
+            webhook_urls: Vec::new(),
+            hooks: Vec::new(),
+            hook_timeout_s: 30,
+            mqtt_enabled: false,
+            mqtt_host: "localhost".to_string(),
+            mqtt_port: 1883,
+            mqtt_username: String::new(),
+            mqtt_password: String::new(),
+            mqtt_topic_prefix: "stretchia".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldType {
+    Integer,
+    Number,
+    Boolean,
+    Enum,
+    /// Several lines of text.
+    List,
+    Text,
+    /// Text that isn't shown, in the form or in the history.
+    Password,
+}
+
+/// What the settings history shows for a password that was set.
+pub const HIDDEN: &str = "••••••";
+
+/// Describes one setting, so the settings window can be generated from it.
+/// Ranges here are also what `Settings::validate` enforces.
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldSpec {
+    pub key: &'static str,
+    #[serde(rename = "type")]
+    pub field_type: FieldType,
+    pub group: &'static str,
+    pub label: &'static str,
+    pub description: &'static str,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub step: Option<f64>,
+    /// Allowed values of an enum and their labels.
+    pub options: &'static [(&'static str, &'static str)],
+}
+
+const fn field(
+    key: &'static str,
+    field_type: FieldType,
+    group: &'static str,
+    label: &'static str,
+    description: &'static str,
+) -> FieldSpec {
+    FieldSpec {
+        key,
+        field_type,
+        group,
+        label,
+        description,
+        min: None,
+        max: None,
+        step: None,
+        options: &[],
+    }
+}
+
+const fn ranged(mut spec: FieldSpec, min: f64, max: f64, step: f64) -> FieldSpec {
+    spec.min = Some(min);
+    spec.max = Some(max);
+    spec.step = Some(step);
+    spec
+}
+
+/// All settings in display order.
+pub const FIELDS: &[FieldSpec] = &[
+    ranged(
+        field("afk_threshold_min", FieldType::Integer, "Timer", "AFK Threshold (minutes)", "Pause timer after this many minutes idle"),
+        1.0, 60.0, 1.0,
+    ),
+    ranged(
+        field("warn_at_min", FieldType::Integer, "Timer", "Warning Start (minutes)", "When orange pulsing begins"),
+        10.0, 120.0, 5.0,
+    ),
+    ranged(
+        field("shake_at_min", FieldType::Integer, "Timer", "Shake Start (minutes)", "When window shaking begins"),
+        30.0, 180.0, 5.0,
+    ),
+    FieldSpec {
+        options: &[("false", "No"), ("true", "Yes")],
+        ..field("reset_timer_on_new_day", FieldType::Boolean, "Timer", "New Day Resets Timer", "Don't carry last night's sitting into the morning")
+    },
+    ranged(
+        field("window_opacity", FieldType::Number, "Window", "Window Opacity", "How see-through the timer window is"),
+        0.3, 1.0, 0.05,
+    ),
+    ranged(
+        field("history_dots_count", FieldType::Integer, "Window", "History Dots", "Number of activity dots shown"),
+        3.0, 20.0, 1.0,
+    ),
+    FieldSpec {
+        options: &[
+            ("top-left", "Top Left"),
+            ("top-right", "Top Right"),
+            ("bottom-left", "Bottom Left"),
+            ("bottom-right", "Bottom Right"),
+        ],
+        ..field("window_anchor", FieldType::Enum, "Window", "Window Anchor Corner", "Corner that stays fixed when resizing")
+    },
+    ranged(
+        field("trash_retention_days", FieldType::Integer, "History", "Keep Deleted Entries (days)", "Trash is emptied automatically after this"),
+        1.0, 365.0, 1.0,
+    ),
+    ranged(
+        field("day_start_hour", FieldType::Integer, "History", "Day Starts At (hour)", "Activity before this hour counts towards the previous day"),
+        0.0, 12.0, 1.0,
+    ),
+    FieldSpec {
+        options: &[("false", "Off"), ("true", "On")],
+        ..field("api_enabled", FieldType::Boolean, "API", "Local HTTP API", "Serve status and actions on localhost for scripts")
+    },
+    ranged(
+        field("api_port", FieldType::Integer, "API", "API Port", "Port on 127.0.0.1 the API listens on"),
+        1024.0, 65535.0, 1.0,
+    ),
+    FieldSpec {
+        options: &[("false", "Off"), ("true", "On")],
+        ..field("api_metrics", FieldType::Boolean, "API", "Prometheus Metrics", "Also serve /metrics for scraping, with the same token")
+    },
+    field("webhook_urls", FieldType::List, "Webhooks", "Webhook URLs", "One per line; each gets a signed POST for timer and workout events"),
+    field("hooks", FieldType::List, "Hooks", "Hook Commands", "One per line as `event: command`, e.g. `stage.critical: notify-send Stand up`"),
+    ranged(
+        field("hook_timeout_s", FieldType::Integer, "Hooks", "Hook Timeout (seconds)", "Commands still running after this are stopped"),
+        1.0, 600.0, 1.0,
+    ),
+    FieldSpec {
+        options: &[("false", "Off"), ("true", "On")],
+        ..field("mqtt_enabled", FieldType::Boolean, "MQTT", "MQTT Publisher", "Publish the timer to a broker for home automation")
+    },
+    field("mqtt_host", FieldType::Text, "MQTT", "Broker Host", "Name or address of the MQTT broker"),
+    ranged(
+        field("mqtt_port", FieldType::Integer, "MQTT", "Broker Port", "Usually 1883"),
+        1.0, 65535.0, 1.0,
+    ),
+    field("mqtt_username", FieldType::Text, "MQTT", "Username", "Leave empty if the broker allows anonymous clients"),
+    field("mqtt_password", FieldType::Password, "MQTT", "Password", "Stored with the other settings, unencrypted"),
+    field("mqtt_topic_prefix", FieldType::Text, "MQTT", "Topic Prefix", "State goes to <prefix>/stage etc., commands are read from <prefix>/command"),
+];
+
+/// A field of the schema returned to the frontend, with its default filled in.
+#[derive(Debug, Clone, Serialize)]
+pub struct SchemaField {
+    #[serde(flatten)]
+    pub spec: FieldSpec,
+    pub default: Value,
+}
+
+pub fn schema() -> Vec<SchemaField> {
+    let defaults = Settings::default().to_map();
+    FIELDS
+        .iter()
+        .map(|spec| SchemaField {
+            spec: spec.clone(),
+            default: defaults.get(spec.key).cloned().unwrap_or(Value::Null),
+        })
+        .collect()
+}
+
+/// A setting that failed validation, reported with `ErrorCode::Invalid`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl Settings {
+    pub fn afk_threshold_s(&self) -> u64 {
+        self.afk_threshold_min * 60
+    }
+
+    /// Seconds after local midnight at which a new day begins.
+    pub fn day_start_s(&self) -> i64 {
+        self.day_start_hour as i64 * 3600
+    }
+
+    pub fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+        let values = self.to_map();
+        for spec in FIELDS {
+            let (Some(min), Some(max)) = (spec.min, spec.max) else {
+                continue;
+            };
+            let value = values.get(spec.key).and_then(Value::as_f64).unwrap_or(f64::NAN);
+            if !(min..=max).contains(&value) {
+                errors.push(FieldError {
+                    field: spec.key.to_string(),
+                    message: format!("Must be between {} and {}", min, max),
+                });
+            }
+        }
+        for url in &self.webhook_urls {
+            let parsed = url::Url::parse(url);
+            if !parsed.is_ok_and(|u| matches!(u.scheme(), "http" | "https") && u.has_host()) {
+                errors.push(FieldError {
+                    field: "webhook_urls".to_string(),
+                    message: format!("Not an http(s) URL: {}", url),
+                });
+            }
+        }
+        for line in &self.hooks {
+            if let Err(message) = hooks::parse(line) {
+                errors.push(FieldError {
+                    field: "hooks".to_string(),
+                    message,
+                });
+            }
+        }
+        if self.mqtt_enabled && self.mqtt_host.trim().is_empty() {
+            errors.push(FieldError {
+                field: "mqtt_host".to_string(),
+                message: "Needed to connect".to_string(),
+            });
+        }
+        let prefix = &self.mqtt_topic_prefix;
+        if prefix.is_empty() || prefix.starts_with('/') || prefix.ends_with('/') || prefix.contains(['+', '#']) {
+            errors.push(FieldError {
+                field: "mqtt_topic_prefix".to_string(),
+                message: "Must be a topic without wildcards or leading and trailing slashes".to_string(),
+            });
+        }
+        if self.warn_at_min >= self.shake_at_min {
+            errors.push(FieldError {
+                field: "shake_at_min".to_string(),
+                message: "Must be later than the warning start".to_string(),
+            });
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn to_map(&self) -> Map<String, Value> {
+        match serde_json::to_value(self) {
+            Ok(Value::Object(map)) => map,
+            _ => Map::new(),
+        }
+    }
+
+    pub fn is_setting(key: &str) -> bool {
+        FIELDS.iter().any(|f| f.key == key)
+    }
+
+    pub fn is_password(key: &str) -> bool {
+        FIELDS.iter().any(|f| f.key == key && matches!(f.field_type, FieldType::Password))
+    }
+
+    /// `value` as the settings history records it: a password only as whether
+    /// one was set, so the history never hands it out.
+    pub fn for_history<'a>(key: &str, value: &'a str) -> &'a str {
+        if Settings::is_password(key) && !value.is_empty() {
+            HIDDEN
+        } else {
+            value
+        }
+    }
+
+    /// Sets one field from its stored text form.
+    pub fn set(&mut self, key: &str, raw: &str) -> Result<(), String> {
+        // Numbers and booleans are stored as JSON, strings as they are
+        let value = serde_json::from_str(raw).unwrap_or_else(|_| Value::String(raw.to_string()));
+        if value.is_string() {
+            return self.set_value(key, value);
+        }
+        // Unless it's a string that happens to read as JSON, e.g. a numeric password
+        self.set_value(key, value)
+            .or_else(|e| self.set_value(key, Value::String(raw.to_string())).map_err(|_| e))
+    }
+
+    pub fn set_value(&mut self, key: &str, value: Value) -> Result<(), String> {
+        let mut map = self.to_map();
+        if !map.contains_key(key) {
+            return Err(format!("Unknown setting: {}", key));
+        }
+        map.insert(key.to_string(), value);
+        *self = serde_json::from_value(Value::Object(map)).map_err(|e| format!("Invalid value for {}: {}", key, e))?;
+        Ok(())
+    }
+
+    /// Applies every value of `values`, collecting an error for each one that
+    /// doesn't fit.
+    pub fn merge(&mut self, values: &Map<String, Value>) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+        for (key, value) in values {
+            if let Err(message) = self.set_value(key, value.clone()) {
+                errors.push(FieldError {
+                    field: key.clone(),
+                    message,
+                });
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Every field in the text form it is stored in.
+    pub fn to_pairs(&self) -> Vec<(String, String)> {
+        self.to_map()
+            .into_iter()
+            .map(|(key, value)| match value {
+                Value::String(s) => (key, s),
+                other => (key, other.to_string()),
+            })
+            .collect()
+    }
+
+    /// Values saved in the `settings` table, without the settings file.
+    pub fn load_stored(conn: &Connection) -> rusqlite::Result<Settings> {
+        let mut settings = Settings::default();
+        for row in db::load_settings(conn)? {
+            // Other rows (e.g. the window position) aren't settings
+            if !Settings::is_setting(&row.key) {
+                continue;
+            }
+            if let Err(e) = settings.set(&row.key, &row.value) {
+                log::warn!("{}", e);
+            }
+        }
+        Ok(settings)
+    }
+
+    /// The settings in effect: stored values with the settings file on top.
+    /// Values that can't be parsed or are out of range are replaced by their
+    /// defaults, so the result is always valid.
+    pub fn load(conn: &Connection) -> rusqlite::Result<Settings> {
+        let mut settings = Settings::load_stored(conn)?;
+        if let Err(errors) = settings.merge(&settings_file::overlay()) {
+            for e in errors {
+                log::warn!("{}: {}", settings_file::path().display(), e.message);
+            }
+        }
+        if let Err(errors) = settings.validate() {
+            let defaults = Settings::default();
+            let mut map = settings.to_map();
+            for e in errors {
+                log::warn!("Resetting {}: {}", e.field, e.message);
+                if let Some(value) = defaults.to_map().remove(&e.field) {
+                    map.insert(e.field, value);
+                }
+            }
+            settings = serde_json::from_value(Value::Object(map)).unwrap_or_default();
+            // A warning start past the (default) shake start is left over
+            if settings.validate().is_err() {
+                settings.warn_at_min = defaults.warn_at_min;
+                settings.shake_at_min = defaults.shake_at_min;
+            }
+        }
+        Ok(settings)
+    }
+
+    /// Writes the fields that changed and records them in the settings
+    /// history, tagged with `source`. Callers validate first.
+    pub fn save(&self, conn: &Connection, source: &str) -> rusqlite::Result<()> {
+        let mut before: HashMap<String, String> = Settings::default().to_pairs().into_iter().collect();
+        // A value never saved is the default, so saving it again isn't a change
+        before.extend(db::load_settings(conn)?.into_iter().map(|row| (row.key, row.value)));
+        let changes: Vec<_> = self
+            .to_pairs()
+            .into_iter()
+            .map(|(key, value)| {
+                let old = before.remove(&key).unwrap_or_default();
+                (key, old, value)
+            })
+            .collect();
+        db::update_settings_logged(conn, &changes, source)
+    }
+
+    /// Like `save`, but the keys set in `file` (the settings file's values)
+    /// keep what is stored for them. The window shows the file's values there;
+    /// saving them would keep them around after the file drops the key.
+    pub fn save_under_file(&self, conn: &Connection, file: &Map<String, Value>, source: &str) -> rusqlite::Result<()> {
+        let stored: HashMap<String, String> = Settings::load_stored(conn)?.to_pairs().into_iter().collect();
+        let mut settings = self.clone();
+        for key in file.keys() {
+            if let Some(value) = stored.get(key) {
+                if let Err(e) = settings.set(key, value) {
+                    log::warn!("{}", e);
+                }
+            }
+        }
+        settings.save(conn, source)
+    }
+
+    /// The stored settings as they were right after history batch `batch`.
+    pub fn at_batch(conn: &Connection, batch: i64) -> rusqlite::Result<Settings> {
+        let mut settings = Settings::load_stored(conn)?;
+        for (key, value) in db::settings_after_batch(conn, batch)? {
+            // The history doesn't know the password; it stays as stored
+            if value == HIDDEN {
+                continue;
+            }
+            if let Err(e) = settings.set(&key, &value) {
+                log::warn!("{}", e);
+            }
+        }
+        Ok(settings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+
+    #[test]
+    fn every_setting_has_a_field_spec() {
+        let Value::Object(defaults) = serde_json::to_value(Settings::default()).unwrap() else {
+            panic!("Settings don't serialize to an object");
+        };
+        let settings: BTreeSet<&str> = defaults.keys().map(String::as_str).collect();
+        let fields: BTreeSet<&str> = FIELDS.iter().map(|f| f.key).collect();
+        assert_eq!(settings, fields);
+        assert_eq!(fields.len(), FIELDS.len(), "a key is listed twice in FIELDS");
+    }
+
+    #[test]
+    fn saving_from_the_window_leaves_file_values_out() {
+        let conn = db::open_in_memory();
+        let stored = Settings {
+            warn_at_min: 30,
+            ..Settings::default()
+        };
+        stored.save(&conn, "window").unwrap();
+
+        let mut file = Map::new();
+        file.insert("warn_at_min".to_string(), Value::from(50));
+        // What the window posts: the file's value for the overridden key
+        let mut posted = stored.clone();
+        posted.warn_at_min = 50;
+        posted.afk_threshold_min = 10;
+        posted.save_under_file(&conn, &file, "window").unwrap();
+
+        let saved = Settings::load_stored(&conn).unwrap();
+        assert_eq!(saved.warn_at_min, 30);
+        assert_eq!(saved.afk_threshold_min, 10);
+        let latest = &db::get_settings_history(&conn, 1).unwrap()[0];
+        let keys: Vec<&str> = latest.changes.iter().map(|c| c.key.as_str()).collect();
+        assert_eq!(keys, ["afk_threshold_min"]);
+    }
+
+    #[test]
+    fn the_history_keeps_passwords_out() {
+        let conn = db::open_in_memory();
+        let mut settings = Settings {
+            mqtt_password: "hunter2".to_string(),
+            ..Settings::default()
+        };
+        settings.save(&conn, "window").unwrap();
+        settings.mqtt_password = "correct horse".to_string();
+        settings.save(&conn, "window").unwrap();
+
+        let history = db::get_settings_history(&conn, 10).unwrap();
+        let values: Vec<(&str, &str)> = history
+            .iter()
+            .flat_map(|set| &set.changes)
+            .map(|c| (c.old_value.as_str(), c.new_value.as_str()))
+            .collect();
+        assert_eq!(values, [(HIDDEN, HIDDEN), ("", HIDDEN)]);
+
+        // Rolling back to the first save keeps the current password
+        let first = history.last().unwrap().batch;
+        assert_eq!(Settings::at_batch(&conn, first).unwrap().mqtt_password, "correct horse");
+    }
+}
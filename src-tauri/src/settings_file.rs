@@ -0,0 +1,133 @@
+use std::path::PathBuf;
+use std::sync::RwLock;
+use std::time::{Duration, SystemTime};
+
+use serde_json::{Map, Value};
+
+use crate::error::{self, Error, ErrorCode};
+use crate::paths;
+use crate::settings::Settings;
+
+/// How often the file is checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Settings left out of an export: secrets, and values that only make sense on
+/// this machine. `window_*` settings are machine-specific as well.
+const NOT_EXPORTED: &[&str] = &["mqtt_password", "api_port"];
+
+/// Values from the settings file, applied over the `settings` table.
+static OVERLAY: RwLock<Option<Map<String, Value>>> = RwLock::new(None);
+
+/// `settings.toml` in the data directory. Keys set there win over the values
+/// saved from the settings window, so a shared team configuration can be
+/// dropped in as is.
+pub fn path() -> PathBuf {
+    paths::data_dir().join("settings.toml")
+}
+
+/// Reads and parses the settings file. A missing file is an empty one.
+pub fn read() -> error::Result<Map<String, Value>> {
+    let text = match std::fs::read_to_string(path()) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Map::new()),
+        Err(e) => return Err(Error::new(ErrorCode::Io, format!("Could not read {}: {}", path().display(), e))),
+    };
+    let table: toml::Table =
+        toml::from_str(&text).map_err(|e| Error::invalid(format!("Invalid {}: {}", path().display(), e)))?;
+    match serde_json::to_value(table) {
+        Ok(Value::Object(map)) => Ok(map),
+        _ => Ok(Map::new()),
+    }
+}
+
+/// Current overlay, read from disk on first use.
+pub fn overlay() -> Map<String, Value> {
+    if let Some(map) = OVERLAY.read().ok().and_then(|o| o.clone()) {
+        return map;
+    }
+    reload();
+    OVERLAY.read().ok().and_then(|o| o.clone()).unwrap_or_default()
+}
+
+/// Re-reads the file. On a parse error the previous overlay is kept, so a
+/// half-saved edit doesn't flip settings back and forth.
+fn reload() {
+    match read() {
+        Ok(map) => {
+            if let Ok(mut overlay) = OVERLAY.write() {
+                *overlay = Some(map);
+            }
+        }
+        Err(e) => log::warn!("{}", e),
+    }
+}
+
+/// The part of `settings` worth sharing: values that differ from the defaults,
+/// without the ones in `NOT_EXPORTED`.
+fn exported(settings: &Settings) -> error::Result<Map<String, Value>> {
+    let (Value::Object(values), Value::Object(defaults)) =
+        (serde_json::to_value(settings)?, serde_json::to_value(Settings::default())?)
+    else {
+        return Err(Error::internal("Settings don't serialize to a table"));
+    };
+    Ok(values
+        .into_iter()
+        .filter(|(key, value)| defaults.get(key) != Some(value))
+        .filter(|(key, _)| !NOT_EXPORTED.contains(&key.as_str()) && !key.starts_with("window_"))
+        .collect())
+}
+
+/// Writes the shareable part of `settings` (see `exported`) to the file,
+/// replacing it.
+pub fn export(settings: &Settings) -> error::Result<PathBuf> {
+    let text = toml::to_string_pretty(&exported(settings)?).map_err(|e| Error::internal(e.to_string()))?;
+    let path = path();
+    std::fs::write(&path, text)
+        .map_err(|e| Error::new(ErrorCode::Io, format!("Could not write {}: {}", path.display(), e)))?;
+    reload();
+    Ok(path)
+}
+
+fn modified() -> Option<SystemTime> {
+    std::fs::metadata(path()).and_then(|m| m.modified()).ok()
+}
+
+/// Polls the file and calls `on_change` after it was created, edited or removed.
+pub fn watch(on_change: impl Fn() + Send + 'static) {
+    std::thread::spawn(move || {
+        let mut last = modified();
+        loop {
+            std::thread::sleep(POLL_INTERVAL);
+            let current = modified();
+            if current != last {
+                last = current;
+                reload();
+                on_change();
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::WindowAnchor;
+
+    #[test]
+    fn export_leaves_out_defaults_secrets_and_machine_settings() {
+        let settings = Settings {
+            warn_at_min: Settings::default().warn_at_min + 5,
+            mqtt_host: "broker.example.com".into(),
+            mqtt_password: "hunter2".into(),
+            api_port: 9999,
+            window_opacity: 0.5,
+            window_anchor: WindowAnchor::TopLeft,
+            ..Settings::default()
+        };
+        let map = exported(&settings).unwrap();
+        let mut keys: Vec<&str> = map.keys().map(String::as_str).collect();
+        keys.sort();
+        assert_eq!(keys, ["mqtt_host", "warn_at_min"]);
+        assert!(exported(&Settings::default()).unwrap().is_empty());
+    }
+}
@@ -1,5 +1,7 @@
 use serde::Serialize;
 
+use crate::settings::Settings;
+
 #[derive(Debug, Clone, PartialEq, Serialize)]
 pub enum Mode {
     Sitting,
@@ -25,6 +27,35 @@ impl Stage {
             Stage::Critical => "red", // same color, different behavior
         }
     }
+
+    /// 0 for green up to 4 for critical.
+    pub fn level(&self) -> u8 {
+        match self {
+            Stage::Green => 0,
+            Stage::Yellow => 1,
+            Stage::Orange => 2,
+            Stage::Red => 3,
+            Stage::Critical => 4,
+        }
+    }
+
+    /// Unlike `as_str`, tells critical from red.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Stage::Critical => "critical",
+            other => other.as_str(),
+        }
+    }
+}
+
+/// The part of `TimerState` that timer actions change, kept to undo them.
+#[derive(Debug, Clone)]
+pub struct TimerSnapshot {
+    pub mode: Mode,
+    pub elapsed_s: u64,
+    pub treadmill_start: Option<i64>,
+    pub sitting_before_s: u64,
+    pub snoozed_until: Option<i64>,
 }
 
 #[derive(Debug, Clone)]
@@ -37,23 +68,59 @@ pub struct TimerState {
     pub afk_threshold_s: u64,
     pub warn_at_min: u64,
     pub shake_at_min: u64,
+    /// Seconds after local midnight at which a new day begins.
+    pub day_start_s: i64,
+    /// Whether the first activity of a new day starts the sitting timer over.
+    pub reset_on_new_day: bool,
+    /// Day of the last active tick, to notice the first activity of a new one.
+    pub active_day: Option<String>,
+    /// Until when (unix time) reminders are held back at green.
+    pub snoozed_until: Option<i64>,
 }
 
 impl TimerState {
-    pub fn new() -> Self {
-        Self {
+    pub fn new(settings: &Settings) -> Self {
+        let mut timer = Self {
             mode: Mode::Sitting,
             elapsed_s: 0,
             treadmill_start: None,
             sitting_before_s: 0,
             is_afk: false,
-            afk_threshold_s: 300, // 5 minutes default
-            warn_at_min: 30,
-            shake_at_min: 60,
-        }
+            afk_threshold_s: 0,
+            warn_at_min: 0,
+            shake_at_min: 0,
+            day_start_s: 0,
+            reset_on_new_day: false,
+            active_day: None,
+            snoozed_until: None,
+        };
+        timer.apply_settings(settings);
+        timer
+    }
+
+    /// Copies the timer-related settings.
+    pub fn apply_settings(&mut self, settings: &Settings) {
+        self.afk_threshold_s = settings.afk_threshold_s();
+        self.warn_at_min = settings.warn_at_min;
+        self.shake_at_min = settings.shake_at_min;
+        self.day_start_s = settings.day_start_s();
+        self.reset_on_new_day = settings.reset_timer_on_new_day;
+    }
+
+    pub fn is_snoozed(&self) -> bool {
+        self.snoozed_until.is_some_and(|until| chrono::Utc::now().timestamp() < until)
+    }
+
+    /// Holds the timer at green for `minutes`. It keeps counting, so reminders
+    /// pick up at the right stage once the snooze is over.
+    pub fn snooze(&mut self, minutes: u64) {
+        self.snoozed_until = Some(chrono::Utc::now().timestamp() + minutes as i64 * 60);
     }
 
     pub fn calculate_stage(&self) -> Stage {
+        if self.is_snoozed() {
+            return Stage::Green;
+        }
         let minutes = self.elapsed_s / 60;
         let warn = self.warn_at_min;
         let shake = self.shake_at_min;
@@ -73,7 +140,25 @@ impl TimerState {
         }
     }
 
+    /// Called on every active tick with the day it falls on. On the first
+    /// activity of a new day the sitting streak from the evening before is
+    /// dropped if `reset_on_new_day` is set; a running treadmill is left alone.
+    /// Returns true if the timer was reset.
+    pub fn roll_over(&mut self, day: &str) -> bool {
+        if self.active_day.as_deref() == Some(day) {
+            return false;
+        }
+        let new_day = self.active_day.is_some();
+        self.active_day = Some(day.to_string());
+        if new_day && self.reset_on_new_day && self.mode == Mode::Sitting {
+            self.elapsed_s = 0;
+            return true;
+        }
+        false
+    }
+
     pub fn reset(&mut self) {
+        self.snoozed_until = None;
         self.sitting_before_s = self.elapsed_s;
         self.elapsed_s = 0;
         self.mode = Mode::Sitting;
@@ -81,12 +166,39 @@ impl TimerState {
     }
 
     pub fn start_treadmill(&mut self) {
+        self.snoozed_until = None;
         self.sitting_before_s = self.elapsed_s;
         self.elapsed_s = 0;
         self.mode = Mode::Treadmill;
         self.treadmill_start = Some(chrono::Utc::now().timestamp());
     }
 
+    pub fn snapshot(&self) -> TimerSnapshot {
+        TimerSnapshot {
+            mode: self.mode.clone(),
+            elapsed_s: self.elapsed_s,
+            treadmill_start: self.treadmill_start,
+            sitting_before_s: self.sitting_before_s,
+            snoozed_until: self.snoozed_until,
+        }
+    }
+
+    /// Puts the timer back to `snapshot`. After an undone stretch or skip the
+    /// time sat since still belongs to the restored streak, so it is carried
+    /// over; time walked after an undone treadmill start isn't sitting, and a
+    /// walk brought back goes on from where it was stopped. A snooze the
+    /// action cleared comes back if it hasn't run out meanwhile.
+    pub fn restore(&mut self, snapshot: TimerSnapshot) {
+        self.elapsed_s = match (&self.mode, &snapshot.mode) {
+            (Mode::Sitting, Mode::Sitting) => snapshot.elapsed_s + self.elapsed_s,
+            _ => snapshot.elapsed_s,
+        };
+        self.mode = snapshot.mode;
+        self.treadmill_start = snapshot.treadmill_start;
+        self.sitting_before_s = snapshot.sitting_before_s;
+        self.snoozed_until = snapshot.snoozed_until;
+    }
+
     pub fn stop_treadmill(&mut self) -> (i64, u64, u64) {
         let started = self.treadmill_start.unwrap_or(chrono::Utc::now().timestamp());
         let duration = self.elapsed_s;
@@ -106,4 +218,68 @@ pub struct TimerTickPayload {
     pub stage: String,
     pub is_afk: bool,
     pub is_treadmill: bool,
+    pub can_undo: bool,
+    /// Unix time the current snooze ends, if any.
+    pub snoozed_until: Option<i64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sitting_for(elapsed_s: u64) -> TimerState {
+        let mut timer = TimerState::new(&Settings::default());
+        timer.elapsed_s = elapsed_s;
+        timer
+    }
+
+    #[test]
+    fn undoing_a_break_keeps_the_time_sat_since() {
+        let mut timer = sitting_for(3000);
+        let before = timer.snapshot();
+        timer.reset();
+        timer.elapsed_s = 60;
+        timer.restore(before);
+        assert_eq!(timer.mode, Mode::Sitting);
+        assert_eq!(timer.elapsed_s, 3060);
+    }
+
+    #[test]
+    fn undoing_a_treadmill_start_drops_the_time_walked() {
+        let mut timer = sitting_for(3000);
+        let before = timer.snapshot();
+        timer.start_treadmill();
+        timer.elapsed_s = 90;
+        timer.restore(before);
+        assert_eq!(timer.mode, Mode::Sitting);
+        assert_eq!(timer.elapsed_s, 3000);
+        assert_eq!(timer.treadmill_start, None);
+    }
+
+    #[test]
+    fn undoing_a_treadmill_stop_resumes_the_walk() {
+        let mut timer = sitting_for(3000);
+        timer.start_treadmill();
+        timer.elapsed_s = 1200;
+        let started = timer.treadmill_start;
+        let before = timer.snapshot();
+        timer.stop_treadmill();
+        timer.elapsed_s = 30;
+        timer.restore(before);
+        assert_eq!(timer.mode, Mode::Treadmill);
+        assert_eq!(timer.elapsed_s, 1200);
+        assert_eq!(timer.treadmill_start, started);
+        assert_eq!(timer.sitting_before_s, 3000);
+    }
+
+    #[test]
+    fn undoing_brings_back_a_running_snooze() {
+        let mut timer = sitting_for(3000);
+        timer.snooze(10);
+        let before = timer.snapshot();
+        timer.reset();
+        assert!(!timer.is_snoozed());
+        timer.restore(before);
+        assert!(timer.is_snoozed());
+    }
 }
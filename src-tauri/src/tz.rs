@@ -0,0 +1,26 @@
+use chrono::{Local, Offset, TimeZone};
+
+/// UTC offset of the local time zone at `ts`, in seconds. Taken at the moment
+/// in question, so a timestamp on the other side of a DST change gets the
+/// offset that was in effect then.
+pub fn offset_at(ts: i64) -> i32 {
+    offset_in(&Local, ts)
+}
+
+/// UTC offset of `zone` at `ts`, in seconds.
+pub fn offset_in<Z: TimeZone>(zone: &Z, ts: i64) -> i32 {
+    zone.timestamp_opt(ts, 0)
+        .single()
+        .map(|t| t.offset().fix().local_minus_utc())
+        .unwrap_or(0)
+}
+
+/// IANA name of the local time zone, e.g. `Europe/Berlin`. A `TZ` override
+/// wins over the system setting, as it does for the offsets.
+pub fn current_zone() -> Option<String> {
+    std::env::var("TZ")
+        .ok()
+        .map(|v| v.trim_start_matches(':').to_string())
+        .filter(|v| !v.is_empty())
+        .or_else(|| iana_time_zone::get_timezone().ok())
+}
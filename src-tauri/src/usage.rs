@@ -0,0 +1,153 @@
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Local, Timelike};
+use rusqlite::Connection;
+
+use crate::afk::AfkReason;
+use crate::db;
+use crate::presence::{self, Presence};
+use crate::tz;
+
+/// How often buffered usage is written to the database. This is also the most
+/// a crash can lose.
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Usage counted for one hour bucket that hasn't been written yet.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UsageDelta {
+    pub date: String,
+    pub hour: u32,
+    pub active_s: i64,
+    pub afk_s: i64,
+    pub utc_offset_s: i32,
+    pub tz: Option<String>,
+}
+
+/// Accumulates the per-second counts of the tick loop in memory.
+pub struct UsageBuffer {
+    pending: Option<UsageDelta>,
+    last_flush: Instant,
+}
+
+impl UsageBuffer {
+    pub fn new() -> Self {
+        Self {
+            pending: None,
+            last_flush: Instant::now(),
+        }
+    }
+
+    /// Counts a tick taken at `at`. When it falls into a new hour (or day), or
+    /// the UTC offset changed, the finished bucket is returned so it can be
+    /// written right away. Days begin `day_start_s` after local midnight.
+    pub fn add(&mut self, at: DateTime<Local>, day_start_s: i64, active_s: i64, afk_s: i64) -> Option<UsageDelta> {
+        let utc_offset_s = at.offset().local_minus_utc();
+        let date = db::day_of(at.timestamp(), utc_offset_s, day_start_s);
+        let hour = at.hour();
+
+        let finished = match &self.pending {
+            Some(p) if p.date == date && p.hour == hour && p.utc_offset_s == utc_offset_s => None,
+            _ => self.pending.take(),
+        };
+        let pending = self.pending.get_or_insert_with(|| UsageDelta {
+            date,
+            hour,
+            active_s: 0,
+            afk_s: 0,
+            utc_offset_s,
+            tz: tz::current_zone(),
+        });
+        pending.active_s += active_s;
+        pending.afk_s += afk_s;
+        finished
+    }
+
+    pub fn is_due(&self) -> bool {
+        self.last_flush.elapsed() >= FLUSH_INTERVAL
+    }
+
+    /// Counts not handed out yet, as `(active_s, afk_s)`.
+    pub fn unwritten(&self) -> (i64, i64) {
+        self.pending.as_ref().map_or((0, 0), |p| (p.active_s, p.afk_s))
+    }
+
+    /// Hands out everything buffered so far and restarts the flush interval.
+    pub fn take(&mut self) -> Option<UsageDelta> {
+        self.last_flush = Instant::now();
+        self.pending.take()
+    }
+}
+
+fn write_usage(conn: &Connection, delta: &UsageDelta) -> rusqlite::Result<()> {
+    db::update_computer_usage(
+        conn,
+        &delta.date,
+        delta.hour,
+        delta.active_s,
+        delta.afk_s,
+        delta.utc_offset_s,
+        delta.tz.as_deref(),
+    )
+}
+
+/// Usage counters and the presence log. Both are maintained on the database
+/// worker, so the tick loop never waits for a write.
+pub struct Accounting {
+    usage: UsageBuffer,
+    presence: Option<Presence>,
+}
+
+impl Accounting {
+    pub fn new() -> Self {
+        Self {
+            usage: UsageBuffer::new(),
+            presence: None,
+        }
+    }
+
+    /// Accounts for one tick of the timer loop taken at `now`; `last_tick` is
+    /// the timestamp of the previous one.
+    pub fn tick(
+        &mut self,
+        conn: &Connection,
+        afk: Option<AfkReason>,
+        idle_s: u64,
+        last_tick: i64,
+        now: DateTime<Local>,
+        day_start_s: i64,
+    ) -> rusqlite::Result<()> {
+        let ts = now.timestamp();
+        let finished = if afk.is_some() {
+            self.usage.add(now, day_start_s, 0, 1)
+        } else {
+            self.usage.add(now, day_start_s, 1, 0)
+        };
+        if let Some(delta) = finished {
+            write_usage(conn, &delta)?;
+        }
+
+        if ts - last_tick > presence::SUSPEND_GAP_S {
+            presence::track(conn, &mut self.presence, Some(AfkReason::Suspend), last_tick, ts)?;
+        }
+        let since = if afk == Some(AfkReason::Idle) { ts - idle_s as i64 } else { ts };
+        presence::track(conn, &mut self.presence, afk, since, ts)?;
+
+        if self.usage.is_due() {
+            self.flush(conn)?;
+        }
+        Ok(())
+    }
+
+    /// Usage counted but not written yet, as `(active_s, afk_s)`.
+    pub fn unwritten_usage(&self) -> (i64, i64) {
+        self.usage.unwritten()
+    }
+
+    /// Writes buffered usage and the open presence session to the database.
+    pub fn flush(&mut self, conn: &Connection) -> rusqlite::Result<()> {
+        if let Some(delta) = self.usage.take() {
+            write_usage(conn, &delta)?;
+        }
+        presence::flush(conn, &self.presence)
+    }
+}
@@ -0,0 +1,234 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use hmac::{Hmac, Mac};
+use rusqlite::Connection;
+use sha2::Sha256;
+
+use crate::db;
+use crate::db_worker::DbWorker;
+use crate::error::{self, Error};
+use crate::events::Envelope;
+use crate::paths;
+
+// Events are queued in `webhook_deliveries` by whoever notices them, in the
+// same database job, and sent from a thread of their own. The table doubles as
+// the delivery log, and pending deliveries survive a restart.
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Attempts before a delivery is given up on.
+const MAX_ATTEMPTS: i64 = 7;
+/// Wait before the first retry; doubled for each one after it.
+const FIRST_RETRY_S: i64 = 30;
+/// Deliveries handed out per poll, so one slow endpoint can't hold up a
+/// large backlog.
+const BATCH_SIZE: i64 = 20;
+/// Days finished deliveries stay in the log.
+pub const LOG_RETENTION_DAYS: i64 = 30;
+
+/// `webhook-secret` in the data directory, the HMAC-SHA256 key payloads are
+/// signed with.
+pub fn secret_path() -> PathBuf {
+    paths::data_dir().join("webhook-secret")
+}
+
+/// Queues `envelope` for every URL in `urls`, logging failures.
+pub fn enqueue(conn: &Connection, urls: &[String], envelope: &Envelope) {
+    if let Err(e) = try_enqueue(conn, urls, envelope) {
+        log::error!("Failed to queue webhook: {}", e);
+    }
+}
+
+fn try_enqueue(conn: &Connection, urls: &[String], envelope: &Envelope) -> error::Result<()> {
+    if urls.is_empty() {
+        return Ok(());
+    }
+    let payload = serde_json::to_string(envelope)?;
+    let name = envelope.name();
+    for url in urls {
+        db::queue_webhook_delivery(conn, url, &name, &payload)?;
+    }
+    Ok(())
+}
+
+/// `sha256=<hex>` of the HMAC-SHA256 of `body`.
+pub fn sign(secret: &str, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(body.as_bytes());
+    let hex: String = mac.finalize().into_bytes().iter().map(|b| format!("{:02x}", b)).collect();
+    format!("sha256={}", hex)
+}
+
+/// Delay before attempt `attempts + 1`.
+fn backoff_s(attempts: i64) -> i64 {
+    FIRST_RETRY_S << (attempts - 1).clamp(0, 16)
+}
+
+/// POSTs `d`, returning the response code, or the code (if any) and error.
+fn deliver(agent: &ureq::Agent, secret: &str, d: &db::WebhookDelivery) -> Result<u16, (Option<u16>, String)> {
+    let result = agent
+        .post(&d.url)
+        .set("Content-Type", "application/json")
+        .set("User-Agent", concat!("Stretchia/", env!("CARGO_PKG_VERSION")))
+        .set("X-Stretchia-Event", &d.event)
+        // The same for every retry, so receivers can drop duplicates
+        .set("X-Stretchia-Delivery", &d.id.to_string())
+        .set("X-Stretchia-Signature", &sign(secret, &d.payload))
+        .send_string(&d.payload);
+    match result {
+        Ok(response) => Ok(response.status()),
+        Err(ureq::Error::Status(code, response)) => Err((Some(code), format!("{} {}", code, response.status_text()))),
+        Err(e) => Err((None, e.to_string())),
+    }
+}
+
+/// Sends queued deliveries in the background, retrying failed ones with
+/// exponential backoff.
+pub fn start(worker: DbWorker) {
+    std::thread::spawn(move || {
+        let agent = ureq::AgentBuilder::new().timeout(REQUEST_TIMEOUT).build();
+        loop {
+            std::thread::sleep(POLL_INTERVAL);
+            if let Err(e) = send_due(&worker, &agent, &secret_path(), chrono::Utc::now().timestamp()) {
+                log::error!("{}", e);
+            }
+        }
+    });
+}
+
+/// Sends the deliveries due at `now`, signed with the secret in `secret_file`,
+/// and records the outcome of each.
+fn send_due(worker: &DbWorker, agent: &ureq::Agent, secret_file: &Path, now: i64) -> error::Result<()> {
+    let due = worker
+        .call_blocking(move |conn| db::due_webhook_deliveries(conn, now, BATCH_SIZE))
+        .map_err(|e| Error::new(e.code, format!("Failed to load webhook deliveries: {}", e)))?;
+    if due.is_empty() {
+        return Ok(());
+    }
+    // Only created once there is something to sign
+    let secret = paths::secret(secret_file)?;
+
+    for d in due {
+        let attempts = d.attempts + 1;
+        let (status, next_attempt_at, code, error) = match deliver(agent, &secret, &d) {
+            Ok(code) => ("delivered", None, Some(code), None),
+            Err((code, error)) => {
+                log::warn!("Webhook {} to {} failed: {}", d.id, d.url, error);
+                if attempts >= MAX_ATTEMPTS {
+                    ("failed", None, code, Some(error))
+                } else {
+                    ("pending", Some(now + backoff_s(attempts)), code, Some(error))
+                }
+            }
+        };
+        worker
+            .call_blocking(move |conn| {
+                db::record_webhook_attempt(conn, d.id, status, next_attempt_at, code, error.as_deref())
+            })
+            .map_err(|e| Error::new(e.code, format!("Failed to record webhook delivery {}: {}", d.id, e)))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use tiny_http::{Response, Server};
+
+    use super::*;
+
+    /// What the receiver got, as `(headers, body)`.
+    type Received = (Vec<(String, String)>, String);
+
+    /// A receiver on a free local port that answers with `statuses` in turn.
+    fn receiver(statuses: &'static [u16]) -> (String, mpsc::Receiver<Received>) {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", server.server_addr().to_ip().unwrap());
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            for &status in statuses {
+                let mut request = server.recv().unwrap();
+                let headers = request
+                    .headers()
+                    .iter()
+                    .map(|h| (h.field.to_string(), h.value.to_string()))
+                    .collect();
+                let mut body = String::new();
+                request.as_reader().read_to_string(&mut body).unwrap();
+                request.respond(Response::empty(status)).unwrap();
+                tx.send((headers, body)).unwrap();
+            }
+        });
+        (url, rx)
+    }
+
+    fn header<'a>(received: &'a Received, name: &str) -> &'a str {
+        received
+            .0
+            .iter()
+            .find(|(field, _)| field.eq_ignore_ascii_case(name))
+            .map_or("", |(_, value)| value)
+    }
+
+    fn delivery(worker: &DbWorker, id: i64) -> db::WebhookDelivery {
+        worker
+            .call_blocking(move |conn| db::get_webhook_deliveries(conn, 10))
+            .unwrap()
+            .into_iter()
+            .find(|d| d.id == id)
+            .unwrap()
+    }
+
+    #[test]
+    fn retries_a_failed_delivery_and_logs_every_attempt() {
+        let (url, received) = receiver(&[500, 204]);
+        let worker = DbWorker::spawn(db::open_in_memory());
+        let payload = r#"{"event":"stretch","data":{}}"#;
+        let id = worker
+            .call_blocking(move |conn| db::queue_webhook_delivery(conn, &url, "stretch", payload))
+            .unwrap();
+        let secret_file = std::env::temp_dir().join(format!("stretchia-test-secret-{}", std::process::id()));
+        let _ = std::fs::remove_file(&secret_file);
+        let agent = ureq::AgentBuilder::new().timeout(REQUEST_TIMEOUT).build();
+        let now = chrono::Utc::now().timestamp();
+
+        send_due(&worker, &agent, &secret_file, now).unwrap();
+        let first = received.recv().unwrap();
+        let secret = std::fs::read_to_string(&secret_file).unwrap();
+        assert_eq!(first.1, payload);
+        assert_eq!(header(&first, "X-Stretchia-Signature"), sign(&secret, payload));
+        assert_eq!(header(&first, "X-Stretchia-Event"), "stretch");
+        assert_eq!(header(&first, "X-Stretchia-Delivery"), id.to_string());
+
+        let logged = delivery(&worker, id);
+        assert_eq!(logged.status, "pending");
+        assert_eq!(logged.attempts, 1);
+        assert_eq!(logged.response_code, Some(500));
+        assert_eq!(logged.next_attempt_at, now + FIRST_RETRY_S);
+
+        // Not due again before the backoff is over
+        send_due(&worker, &agent, &secret_file, now + FIRST_RETRY_S - 1).unwrap();
+        assert_eq!(delivery(&worker, id).attempts, 1);
+
+        send_due(&worker, &agent, &secret_file, now + FIRST_RETRY_S).unwrap();
+        let second = received.recv().unwrap();
+        assert_eq!(header(&second, "X-Stretchia-Delivery"), id.to_string());
+        assert_eq!(header(&second, "X-Stretchia-Signature"), sign(&secret, payload));
+
+        let logged = delivery(&worker, id);
+        assert_eq!(logged.status, "delivered");
+        assert_eq!(logged.attempts, 2);
+        assert_eq!(logged.response_code, Some(204));
+        assert_eq!(logged.error, None);
+        let _ = std::fs::remove_file(&secret_file);
+    }
+
+    #[test]
+    fn backoff_doubles_from_the_first_retry() {
+        assert_eq!(backoff_s(1), FIRST_RETRY_S);
+        assert_eq!(backoff_s(2), 2 * FIRST_RETRY_S);
+        assert_eq!(backoff_s(6), 32 * FIRST_RETRY_S);
+    }
+}
@@ -0,0 +1,32 @@
+// What a failed `cmd_*` command rejects with (`error.rs` on the Rust side).
+
+export type ErrorCode =
+  | "invalid"
+  | "not-found"
+  | "conflict"
+  | "constraint"
+  | "busy"
+  | "database"
+  | "io"
+  | "internal";
+
+export interface FieldError {
+  field: string;
+  message: string;
+}
+
+export interface CommandError {
+  code: ErrorCode;
+  message: string;
+  /** The invalid values, when settings were rejected. */
+  fields?: FieldError[];
+}
+
+/** Reads a rejected `invoke` as a `CommandError`; anything else Tauri itself
+ * threw counts as internal. */
+export function commandError(e: unknown): CommandError {
+  if (typeof e === "object" && e !== null && "code" in e && "message" in e) {
+    return e as CommandError;
+  }
+  return { code: "internal", message: String(e) };
+}
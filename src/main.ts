@@ -8,6 +8,7 @@ interface TimerState {
   stage: string;
   is_afk: boolean;
   is_treadmill: boolean;
+  can_undo: boolean;
 }
 
 interface Workout {
@@ -23,6 +24,7 @@ const timerEl = document.getElementById("timer")!;
 const btnStretch = document.getElementById("btn-stretch")!;
 const btnTreadmill = document.getElementById("btn-treadmill")!;
 const btnSkip = document.getElementById("btn-skip")!;
+const btnUndo = document.getElementById("btn-undo")!;
 const btnSettings = document.getElementById("btn-settings")!;
 const btnStats = document.getElementById("btn-stats")!;
 const btnClose = document.getElementById("btn-close")!;
@@ -121,13 +123,8 @@ function updateAnchorIcon() {
 
 async function loadAnchorSetting() {
   try {
-    const settings = await invoke<{ key: string; value: string }[]>("cmd_get_settings");
-    for (const s of settings) {
-      if (s.key === "window_anchor") {
-        anchor = s.value;
-        break;
-      }
-    }
+    const settings = await invoke<{ window_anchor: string }>("cmd_get_settings");
+    anchor = settings.window_anchor;
   } catch (_) {
     // Keep default
   }
@@ -189,6 +186,8 @@ listen<TimerState>("timer-tick", (event) => {
     btnSkip.classList.add("hidden");
   }
 
+  btnUndo.classList.toggle("hidden", !state.can_undo);
+
   // Shake at critical stage every 5 minutes
   if (state.stage === "critical") {
     if (state.elapsed_s > 0 && state.elapsed_s % 300 === 0) {
@@ -216,6 +215,13 @@ btnTreadmill.addEventListener("click", async () => {
   }
 });
 
+// Undo button — reverts an accidental Stretch/Skip/Treadmill click
+btnUndo.addEventListener("click", async () => {
+  await invoke("cmd_undo_timer_action");
+  btnUndo.classList.add("hidden");
+  await loadHistory();
+});
+
 // Skip button — multi-stage guilt trip
 function showSkipStage() {
   skipDialog.classList.remove("hidden");
@@ -354,6 +360,11 @@ getCurrentWindow().onCloseRequested(async (event) => {
   await getCurrentWindow().hide();
 });
 
+// History changed outside this window (e.g. from the tray)
+listen("history-changed", () => {
+  loadHistory();
+});
+
 // Listen for settings changes to update anchor
 listen("settings-changed", () => {
   loadAnchorSetting();
@@ -1,39 +1,312 @@
 import { invoke } from "@tauri-apps/api/core";
 import { getCurrentWindow } from "@tauri-apps/api/window";
+import { listen } from "@tauri-apps/api/event";
+import { commandError, type FieldError } from "./errors";
 
-interface Setting {
+type Value = number | string | boolean | string[];
+type Settings = Record<string, Value>;
+
+interface SchemaField {
   key: string;
-  value: string;
+  type: "integer" | "number" | "boolean" | "enum" | "list" | "text" | "password";
+  group: string;
+  label: string;
+  description: string;
+  min: number | null;
+  max: number | null;
+  step: number | null;
+  options: [string, string][];
+  default: Value;
+}
+
+interface SettingsChangeSet {
+  batch: number;
+  source: "window" | "import" | "rollback";
+  changed_at: number;
+  utc_offset_s: number;
+  changes: { key: string; old_value: string; new_value: string }[];
 }
 
-const fields: Record<string, HTMLInputElement | HTMLSelectElement> = {
-  afk_threshold_min: document.getElementById("afk_threshold_min") as HTMLInputElement,
-  warn_at_min: document.getElementById("warn_at_min") as HTMLInputElement,
-  shake_at_min: document.getElementById("shake_at_min") as HTMLInputElement,
-  window_opacity: document.getElementById("window_opacity") as HTMLInputElement,
-  history_dots_count: document.getElementById("history_dots_count") as HTMLInputElement,
-  window_anchor: document.getElementById("window_anchor") as HTMLSelectElement,
+const SOURCE_LABELS: Record<string, string> = {
+  window: "Saved",
+  import: "Imported",
+  rollback: "Restored",
 };
 
-const opacityDisplay = document.getElementById("opacity-display")!;
+const fieldsEl = document.getElementById("fields")!;
+const historyEl = document.getElementById("history")!;
+const deliveriesEl = document.getElementById("deliveries")!;
 const statusEl = document.getElementById("status")!;
 
-fields.window_opacity.addEventListener("input", () => {
-  opacityDisplay.textContent = parseFloat(fields.window_opacity.value).toFixed(2);
-});
+let schema: SchemaField[] = [];
+type FieldInput = HTMLInputElement | HTMLSelectElement | HTMLTextAreaElement;
+const fields: Record<string, FieldInput> = {};
+// Value labels next to sliders
+const displays: Record<string, HTMLElement> = {};
+
+function createInput(f: SchemaField): FieldInput {
+  if (f.type === "list") {
+    const textarea = document.createElement("textarea");
+    textarea.rows = 3;
+    return textarea;
+  }
+  if (f.type === "boolean" || f.type === "enum") {
+    const select = document.createElement("select");
+    for (const [value, label] of f.options) {
+      const option = document.createElement("option");
+      option.value = value;
+      option.textContent = label;
+      select.appendChild(option);
+    }
+    return select;
+  }
+  const input = document.createElement("input");
+  if (f.type === "text" || f.type === "password") {
+    input.type = f.type;
+    return input;
+  }
+  // Fractional settings are sliders, whole numbers plain number inputs
+  input.type = f.type === "number" ? "range" : "number";
+  if (f.min !== null) input.min = String(f.min);
+  if (f.max !== null) input.max = String(f.max);
+  if (f.step !== null) input.step = String(f.step);
+  return input;
+}
+
+function renderFields() {
+  fieldsEl.innerHTML = "";
+  let group = "";
+  for (const f of schema) {
+    if (f.group !== group) {
+      group = f.group;
+      const heading = document.createElement("h3");
+      heading.textContent = group;
+      fieldsEl.appendChild(heading);
+    }
+
+    const wrapper = document.createElement("div");
+    wrapper.className = "field";
+
+    const label = document.createElement("label");
+    label.htmlFor = f.key;
+    label.textContent = f.label;
+
+    const input = createInput(f);
+    input.id = f.key;
+    fields[f.key] = input;
+
+    wrapper.appendChild(label);
+    wrapper.appendChild(input);
+    if (input instanceof HTMLInputElement && input.type === "range") {
+      const display = document.createElement("span");
+      display.className = "value-display";
+      displays[f.key] = display;
+      input.addEventListener("input", () => updateDisplay(f.key));
+      wrapper.appendChild(display);
+    }
+    const hint = document.createElement("span");
+    hint.className = "hint";
+    hint.textContent = f.description;
+    wrapper.appendChild(hint);
+
+    fieldsEl.appendChild(wrapper);
+  }
+}
+
+function updateDisplay(key: string) {
+  if (displays[key]) {
+    displays[key].textContent = parseFloat(fields[key].value).toFixed(2);
+  }
+}
 
 async function loadSettings() {
   try {
-    const settings = await invoke<Setting[]>("cmd_get_settings");
-    for (const s of settings) {
-      if (fields[s.key]) {
-        fields[s.key].value = s.value;
-      }
+    if (schema.length === 0) {
+      schema = await invoke<SchemaField[]>("cmd_get_settings_schema");
+      renderFields();
     }
-    opacityDisplay.textContent = parseFloat(fields.window_opacity.value).toFixed(2);
+    const settings = await invoke<Settings>("cmd_get_settings");
+    const overrides = await invoke<string[]>("cmd_get_settings_overrides");
+    for (const f of schema) {
+      const value = settings[f.key] ?? f.default;
+      // Lists are edited one entry per line
+      fields[f.key].value = Array.isArray(value) ? value.join("\n") : String(value);
+      // Values from settings.toml win, so editing them here would do nothing
+      const overridden = overrides.includes(f.key);
+      fields[f.key].disabled = overridden;
+      fields[f.key].title = overridden ? "Set in settings.toml" : "";
+      updateDisplay(f.key);
+    }
+    clearFieldErrors();
   } catch (e) {
     showStatus("Failed to load settings", true);
   }
+  loadHistory();
+  loadDeliveries();
+}
+
+// Stored values are JSON for numbers and booleans; show them the way the form does
+function formatValue(key: string, raw: string): string {
+  const f = schema.find((f) => f.key === key);
+  if (f?.type === "password") return raw ? "••••••" : "none";
+  const option = f?.options.find(([value]) => value === raw);
+  if (option) return option[1];
+  if (f?.type === "list") {
+    const items = JSON.parse(raw) as string[];
+    return items.length ? items.join(", ") : "none";
+  }
+  return raw;
+}
+
+function formatWhen(unixTs: number, offsetS: number): string {
+  // Shown in the zone the change was made in
+  const d = new Date((unixTs + offsetS) * 1000);
+  const date = d.toISOString().slice(0, 10);
+  const time = d.toISOString().slice(11, 16);
+  return `${date} ${time}`;
+}
+
+async function loadHistory() {
+  try {
+    const history = await invoke<SettingsChangeSet[]>("cmd_get_settings_history");
+    historyEl.innerHTML = "";
+    if (history.length === 0) {
+      const empty = document.createElement("span");
+      empty.className = "history-empty";
+      empty.textContent = "No changes yet";
+      historyEl.appendChild(empty);
+      return;
+    }
+    history.forEach((set, i) => {
+      const entry = document.createElement("div");
+      entry.className = "history-entry";
+
+      const text = document.createElement("div");
+      text.className = "history-text";
+      const when = document.createElement("div");
+      when.className = "history-when";
+      when.textContent = `${SOURCE_LABELS[set.source] ?? set.source} ${formatWhen(set.changed_at, set.utc_offset_s)}`;
+      text.appendChild(when);
+      for (const c of set.changes) {
+        const line = document.createElement("div");
+        const label = schema.find((f) => f.key === c.key)?.label ?? c.key;
+        line.textContent = `${label}: ${formatValue(c.key, c.old_value)} → ${formatValue(c.key, c.new_value)}`;
+        text.appendChild(line);
+      }
+      entry.appendChild(text);
+
+      // The newest entry is what's saved now, nothing to restore
+      if (i > 0) {
+        const restore = document.createElement("button");
+        restore.textContent = "Restore";
+        restore.title = "Go back to the settings as they were after this change";
+        restore.addEventListener("click", () => rollback(set.batch));
+        entry.appendChild(restore);
+      }
+      historyEl.appendChild(entry);
+    });
+  } catch (e) {
+    console.error("Failed to load settings history:", e);
+  }
+}
+
+interface WebhookDelivery {
+  id: number;
+  url: string;
+  event: string;
+  created_at: number;
+  status: "pending" | "delivered" | "failed";
+  attempts: number;
+  next_attempt_at: number;
+  response_code: number | null;
+  error: string | null;
+}
+
+async function loadDeliveries() {
+  try {
+    const deliveries = await invoke<WebhookDelivery[]>("cmd_get_webhook_deliveries");
+    deliveriesEl.innerHTML = "";
+    if (deliveries.length === 0) {
+      const empty = document.createElement("span");
+      empty.className = "history-empty";
+      empty.textContent = "Nothing sent yet";
+      deliveriesEl.appendChild(empty);
+      return;
+    }
+    for (const d of deliveries) {
+      const entry = document.createElement("div");
+      entry.className = `history-entry delivery ${d.status}`;
+      const text = document.createElement("div");
+      text.className = "history-text";
+      const when = document.createElement("div");
+      when.className = "history-when";
+      const offset = -new Date(d.created_at * 1000).getTimezoneOffset() * 60;
+      when.textContent = `${d.event} ${formatWhen(d.created_at, offset)}`;
+      text.appendChild(when);
+      const url = document.createElement("div");
+      url.textContent = d.url;
+      text.appendChild(url);
+      const outcome = document.createElement("div");
+      const tries = d.attempts === 1 ? "1 attempt" : `${d.attempts} attempts`;
+      if (d.status === "delivered") outcome.textContent = `Delivered (${d.response_code}), ${tries}`;
+      else if (d.status === "failed") outcome.textContent = `Failed after ${tries}: ${d.error}`;
+      else if (d.attempts > 0) outcome.textContent = `Retrying, ${tries} so far: ${d.error}`;
+      else outcome.textContent = "Queued";
+      text.appendChild(outcome);
+      entry.appendChild(text);
+      deliveriesEl.appendChild(entry);
+    }
+  } catch (e) {
+    console.error("Failed to load webhook deliveries:", e);
+  }
+}
+
+async function rollback(batch: number) {
+  try {
+    await invoke("cmd_rollback_settings", { batch });
+    showStatus("Settings restored!", false);
+    loadSettings();
+  } catch (e) {
+    showStatus(`Error: ${commandError(e).message}`, true);
+  }
+}
+
+// Fields set in settings.toml are left out; the backend keeps their stored values
+function readFields(): Settings {
+  const settings: Settings = {};
+  for (const f of schema) {
+    if (fields[f.key].disabled) continue;
+    const value = fields[f.key].value;
+    if (f.type === "integer" || f.type === "number") settings[f.key] = Number(value);
+    else if (f.type === "boolean") settings[f.key] = value === "true";
+    else if (f.type === "list") settings[f.key] = value.split("\n").map((line) => line.trim()).filter((line) => line);
+    else settings[f.key] = value;
+  }
+  return settings;
+}
+
+function clearFieldErrors() {
+  for (const el of document.querySelectorAll(".field-error")) el.remove();
+  for (const input of Object.values(fields)) input.classList.remove("invalid");
+}
+
+// Marks the fields in error; returns the messages that belong to no field
+function showFieldErrors(errors: FieldError[]): string[] {
+  clearFieldErrors();
+  const unplaced: string[] = [];
+  for (const e of errors) {
+    const input = fields[e.field];
+    if (!input) {
+      unplaced.push(e.message);
+      continue;
+    }
+    input.classList.add("invalid");
+    const msg = document.createElement("span");
+    msg.className = "field-error";
+    msg.textContent = e.message;
+    input.insertAdjacentElement("afterend", msg);
+  }
+  return unplaced;
 }
 
 function showStatus(msg: string, isError: boolean) {
@@ -50,19 +323,57 @@ function hideWindow() {
 
 document.getElementById("btn-save")!.addEventListener("click", async () => {
   try {
-    for (const [key, input] of Object.entries(fields)) {
-      await invoke("cmd_update_setting", { key, value: input.value });
-    }
-    await invoke("cmd_apply_settings");
+    await invoke("cmd_save_settings", { settings: readFields() });
+    clearFieldErrors();
     showStatus("Settings saved!", false);
     setTimeout(hideWindow, 800);
   } catch (e) {
-    showStatus(`Error: ${e}`, true);
+    const err = commandError(e);
+    showFieldErrors(err.fields ?? []);
+    showStatus(`Error: ${err.message}`, true);
   }
 });
 
 document.getElementById("btn-cancel")!.addEventListener("click", hideWindow);
 
+document.getElementById("btn-export")!.addEventListener("click", async () => {
+  try {
+    const path = await invoke<string>("cmd_export_settings");
+    showStatus(`Exported to ${path}`, false);
+    loadSettings();
+  } catch (e) {
+    showStatus(`Error: ${commandError(e).message}`, true);
+  }
+});
+
+document.getElementById("btn-import")!.addEventListener("click", async () => {
+  try {
+    await invoke("cmd_import_settings");
+    showStatus("Settings imported!", false);
+    loadSettings();
+  } catch (e) {
+    const err = commandError(e);
+    const unplaced = showFieldErrors(err.fields ?? []);
+    showStatus(`Error: ${[err.message, ...unplaced].join(". ")}`, true);
+  }
+});
+
+document.getElementById("btn-test-webhooks")!.addEventListener("click", async () => {
+  try {
+    await invoke("cmd_test_webhooks");
+    showStatus("Test event queued", false);
+    // Give the first attempt a moment before showing its outcome
+    setTimeout(loadDeliveries, 3000);
+  } catch (e) {
+    showStatus(`Error: ${commandError(e).message}`, true);
+  }
+});
+
+// The settings file was edited and applied
+listen("settings-changed", () => {
+  loadSettings();
+});
+
 // Hide instead of destroy when close button is clicked
 getCurrentWindow().onCloseRequested(async (event) => {
   event.preventDefault();
@@ -1,5 +1,6 @@
 import { invoke } from "@tauri-apps/api/core";
 import { getCurrentWindow } from "@tauri-apps/api/window";
+import { commandError } from "./errors";
 
 interface Workout {
   id: number;
@@ -8,6 +9,44 @@ interface Workout {
   ended_at: number;
   duration_s: number;
   sitting_before_s: number;
+  utc_offset_s: number;
+  tz: string | null;
+}
+
+interface HourlyUsage {
+  date: string;
+  hour: number;
+  active_s: number;
+  afk_s: number;
+}
+
+interface Session {
+  id: number;
+  kind: string;
+  started_at: number;
+  ended_at: number;
+  reason: string | null;
+  utc_offset_s: number;
+  tz: string | null;
+}
+
+interface SettingsChangeSet {
+  batch: number;
+  source: string;
+  changed_at: number;
+  utc_offset_s: number;
+  changes: { key: string; old_value: string; new_value: string }[];
+}
+
+// Settings that change how sitting time is judged, marked on the presence bar
+const THRESHOLD_LABELS: Record<string, string> = {
+  afk_threshold_min: "AFK threshold",
+  warn_at_min: "Warning start",
+  shake_at_min: "Shake start",
+};
+
+interface TrashedWorkout extends Workout {
+  deleted_at: number;
 }
 
 interface DayStats {
@@ -33,9 +72,36 @@ const avgSitting = document.getElementById("avg-sitting")!;
 const maxSitting = document.getElementById("max-sitting")!;
 const afkTime = document.getElementById("afk-time")!;
 const timeline = document.getElementById("timeline")!;
+const hoursEl = document.getElementById("hours")!;
+const presenceEl = document.getElementById("presence")!;
 const emptyState = document.getElementById("empty-state")!;
+const btnAddEntry = document.getElementById("btn-add-entry")!;
+const entryForm = document.getElementById("entry-form") as HTMLFormElement;
+const entryType = document.getElementById("entry-type") as HTMLSelectElement;
+const entryStart = document.getElementById("entry-start") as HTMLInputElement;
+const entryDuration = document.getElementById("entry-duration") as HTMLInputElement;
+const entrySitting = document.getElementById("entry-sitting") as HTMLInputElement;
+const entryError = document.getElementById("entry-error")!;
+const btnTrash = document.getElementById("btn-trash")!;
+const trashEl = document.getElementById("trash")!;
+const trashList = document.getElementById("trash-list")!;
+const trashEmpty = document.getElementById("trash-empty")!;
+const undoBar = document.getElementById("undo-bar")!;
+const undoMessage = document.getElementById("undo-message")!;
+
+// Seconds after midnight at which a day begins (the day_start_hour setting)
+let dayStartS = 0;
+let currentDate = today();
+let editingId: number | null = null;
+let editingOffset = 0;
+let showingTrash = false;
+let undoTimer: number | null = null;
 
-let currentDate = new Date();
+const defaultDurationMin: Record<string, number> = {
+  stretch: 5,
+  treadmill: 30,
+  skip: 0,
+};
 
 function formatDateISO(d: Date): string {
   const y = d.getFullYear();
@@ -44,12 +110,26 @@ function formatDateISO(d: Date): string {
   return `${y}-${m}-${day}`;
 }
 
+// The day that is still running: before the day start it is yesterday
+function today(): Date {
+  return new Date(Date.now() - dayStartS * 1000);
+}
+
+async function loadDayStart() {
+  try {
+    const settings = await invoke<{ day_start_hour: number }>("cmd_get_settings");
+    dayStartS = settings.day_start_hour * 3600;
+  } catch (e) {
+    console.error("Failed to load day start:", e);
+  }
+}
+
 function formatDateDisplay(d: Date): string {
-  const today = new Date();
-  const yesterday = new Date(today);
-  yesterday.setDate(today.getDate() - 1);
+  const current = today();
+  const yesterday = new Date(current);
+  yesterday.setDate(current.getDate() - 1);
 
-  if (formatDateISO(d) === formatDateISO(today)) return "Today";
+  if (formatDateISO(d) === formatDateISO(current)) return "Today";
   if (formatDateISO(d) === formatDateISO(yesterday)) return "Yesterday";
 
   return d.toLocaleDateString(undefined, {
@@ -67,21 +147,212 @@ function formatDuration(totalSeconds: number): string {
   return `${h}h ${m}m`;
 }
 
-function formatTime24(unixTs: number): string {
-  const d = new Date(unixTs * 1000);
-  return d.toLocaleTimeString(undefined, { hour: "2-digit", minute: "2-digit" });
+// Records are shown on the clock of the zone they were made in, so a trip or
+// a DST change doesn't shift them. The returned Date holds that wall-clock
+// time in its UTC fields.
+function wallClock(unixTs: number, offsetS: number): Date {
+  return new Date((unixTs + offsetS) * 1000);
+}
+
+function formatTime24(unixTs: number, offsetS: number): string {
+  return wallClock(unixTs, offsetS).toLocaleTimeString(undefined, {
+    hour: "2-digit",
+    minute: "2-digit",
+    timeZone: "UTC",
+  });
+}
+
+function formatTimeInput(unixTs: number, offsetS: number): string {
+  const d = wallClock(unixTs, offsetS);
+  return `${String(d.getUTCHours()).padStart(2, "0")}:${String(d.getUTCMinutes()).padStart(2, "0")}`;
+}
+
+function describeWorkout(w: Workout): string {
+  if (w.workout_type === "stretch") return "Stretch break";
+  if (w.workout_type === "skip") return "Skipped break";
+  return `Treadmill — ${formatDuration(w.duration_s)}`;
+}
+
+function showUndo(message: string) {
+  undoMessage.textContent = message;
+  undoBar.classList.remove("hidden");
+  if (undoTimer !== null) clearTimeout(undoTimer);
+  undoTimer = window.setTimeout(hideUndo, 10000);
+}
+
+function hideUndo() {
+  undoBar.classList.add("hidden");
+  if (undoTimer !== null) {
+    clearTimeout(undoTimer);
+    undoTimer = null;
+  }
+}
+
+async function undoLastChange() {
+  hideUndo();
+  try {
+    await invoke<string | null>("cmd_undo_workout_change");
+  } catch (e) {
+    console.error("Failed to undo:", e);
+  }
+  await loadStats();
+  if (showingTrash) await loadTrash();
+}
+
+function openEntryForm(w: Workout | null) {
+  editingId = w ? w.id : null;
+  editingOffset = w ? w.utc_offset_s : 0;
+  entryError.classList.add("hidden");
+  if (w) {
+    entryType.value = w.workout_type;
+    entryStart.value = formatTimeInput(w.started_at, w.utc_offset_s);
+    entryDuration.value = String(Math.round(w.duration_s / 60));
+    entrySitting.value = String(Math.round(w.sitting_before_s / 60));
+  } else {
+    entryType.value = "treadmill";
+    entryStart.value = "";
+    entryDuration.value = String(defaultDurationMin.treadmill);
+    entrySitting.value = "";
+  }
+  entryForm.classList.remove("hidden");
+  entryStart.focus();
+}
+
+function closeEntryForm() {
+  editingId = null;
+  entryForm.classList.add("hidden");
+}
+
+async function saveEntry() {
+  const [h, m] = entryStart.value.split(":").map(Number);
+  // Times before the day start belong to the next calendar date
+  const nextDay = (h * 3600 + m * 60) < dayStartS ? 1 : 0;
+  // An edited entry keeps the zone it was recorded in; new ones use this one
+  let startedAt: number;
+  if (editingId !== null) {
+    const wall = Date.UTC(currentDate.getFullYear(), currentDate.getMonth(), currentDate.getDate() + nextDay, h, m);
+    startedAt = wall / 1000 - editingOffset;
+  } else {
+    const start = new Date(currentDate);
+    start.setDate(start.getDate() + nextDay);
+    start.setHours(h, m, 0, 0);
+    startedAt = Math.floor(start.getTime() / 1000);
+  }
+  const endedAt = startedAt + (parseInt(entryDuration.value, 10) || 0) * 60;
+  const sittingMin = entrySitting.value === "" ? null : parseInt(entrySitting.value, 10);
+  const sittingBeforeS = sittingMin === null ? null : sittingMin * 60;
+
+  try {
+    if (editingId !== null) {
+      await invoke("cmd_update_workout", {
+        id: editingId,
+        workoutType: entryType.value,
+        startedAt,
+        endedAt,
+        sittingBeforeS: sittingBeforeS ?? 0,
+      });
+    } else {
+      await invoke("cmd_add_workout", {
+        workoutType: entryType.value,
+        startedAt,
+        endedAt,
+        sittingBeforeS,
+      });
+    }
+    showUndo(editingId !== null ? "Entry updated" : "Entry added");
+    closeEntryForm();
+    loadStats();
+  } catch (e) {
+    const err = commandError(e);
+    if (err.code === "not-found") {
+      // Deleted for good in the meantime; there is nothing left to edit
+      closeEntryForm();
+      loadStats();
+      return;
+    }
+    entryError.textContent = err.message;
+    entryError.classList.remove("hidden");
+  }
+}
+
+async function loadHours(dateStr: string) {
+  try {
+    const usage = await invoke<HourlyUsage[]>("cmd_get_hourly_usage", { from: dateStr, to: dateStr });
+    const byHour = new Map(usage.map((u) => [u.hour, u]));
+    hoursEl.innerHTML = "";
+    const firstHour = Math.floor(dayStartS / 3600);
+    for (let i = 0; i < 24; i++) {
+      const hour = (firstHour + i) % 24;
+      const u = byHour.get(hour);
+      const cell = document.createElement("div");
+      cell.className = "hour-cell";
+      if (hour % 6 === 0) {
+        cell.classList.add("labeled");
+        cell.dataset.hour = String(hour);
+      }
+      if (u && u.active_s > 0) {
+        const share = Math.min(u.active_s / 3600, 1);
+        cell.style.background = `rgba(74, 222, 128, ${0.15 + share * 0.85})`;
+      }
+      const active = u ? formatDuration(u.active_s) : "0s";
+      const afk = u ? formatDuration(u.afk_s) : "0s";
+      cell.title = `${String(hour).padStart(2, "0")}:00 — active ${active}, AFK ${afk}`;
+      hoursEl.appendChild(cell);
+    }
+  } catch (e) {
+    console.error("Failed to load hourly usage:", e);
+  }
+}
+
+async function loadPresence(dateStr: string) {
+  try {
+    const sessions = await invoke<Session[]>("cmd_get_day_sessions", { date: dateStr });
+    // Positions are in wall-clock seconds of each session's own zone
+    const from = Date.UTC(currentDate.getFullYear(), currentDate.getMonth(), currentDate.getDate()) / 1000 + dayStartS;
+    const to = from + 86400;
+
+    presenceEl.innerHTML = "";
+    for (const s of sessions) {
+      const start = Math.max(s.started_at + s.utc_offset_s, from);
+      const end = Math.min(s.ended_at + s.utc_offset_s, to);
+      if (end <= start) continue;
+      const seg = document.createElement("div");
+      seg.className = `presence-seg ${s.kind}`;
+      seg.style.left = `${((start - from) / (to - from)) * 100}%`;
+      seg.style.width = `${((end - start) / (to - from)) * 100}%`;
+      const label = s.kind === "afk" ? `AFK (${s.reason ?? "idle"})` : "Active";
+      seg.title = `${label} ${formatTime24(s.started_at, s.utc_offset_s)}–${formatTime24(s.ended_at, s.utc_offset_s)}`;
+      presenceEl.appendChild(seg);
+    }
+
+    const changeSets = await invoke<SettingsChangeSet[]>("cmd_get_day_settings_changes", { date: dateStr });
+    for (const set of changeSets) {
+      const changes = set.changes.filter((c) => c.key in THRESHOLD_LABELS);
+      const at = set.changed_at + set.utc_offset_s;
+      if (changes.length === 0 || at < from || at >= to) continue;
+      const marker = document.createElement("div");
+      marker.className = "settings-marker";
+      marker.style.left = `${((at - from) / (to - from)) * 100}%`;
+      const lines = changes.map((c) => `${THRESHOLD_LABELS[c.key]}: ${c.old_value} → ${c.new_value} min`);
+      marker.title = `${formatTime24(set.changed_at, set.utc_offset_s)}\n${lines.join("\n")}`;
+      presenceEl.appendChild(marker);
+    }
+  } catch (e) {
+    console.error("Failed to load presence:", e);
+  }
 }
 
 function updateNav() {
-  const today = formatDateISO(new Date());
   const current = formatDateISO(currentDate);
-  btnNext.disabled = current >= today;
+  btnNext.disabled = current >= formatDateISO(today());
   dateLabel.textContent = formatDateDisplay(currentDate);
 }
 
 async function loadStats() {
   updateNav();
   const dateStr = formatDateISO(currentDate);
+  loadHours(dateStr);
+  loadPresence(dateStr);
 
   try {
     const stats = await invoke<DayStats>("cmd_get_day_stats", { date: dateStr });
@@ -100,7 +371,10 @@ async function loadStats() {
 
     // Timeline
     timeline.innerHTML = "";
-    if (stats.workouts.length === 0) {
+    if (showingTrash) {
+      emptyState.classList.add("hidden");
+      timeline.classList.add("hidden");
+    } else if (stats.workouts.length === 0) {
       emptyState.classList.remove("hidden");
       timeline.classList.add("hidden");
     } else {
@@ -113,29 +387,39 @@ async function loadStats() {
 
         const time = document.createElement("span");
         time.className = "tl-time";
-        time.textContent = formatTime24(w.started_at);
+        time.textContent = formatTime24(w.started_at, w.utc_offset_s);
 
         const dot = document.createElement("span");
         dot.className = `tl-dot ${w.workout_type}`;
 
         const desc = document.createElement("span");
         desc.className = "tl-desc";
-        if (w.workout_type === "stretch") {
-          desc.textContent = "Stretch break";
-        } else {
-          desc.textContent = `Treadmill — ${formatDuration(w.duration_s)}`;
-        }
+        desc.textContent = describeWorkout(w);
 
         const sitting = document.createElement("span");
         sitting.className = "tl-sitting";
         sitting.textContent = `after ${formatDuration(w.sitting_before_s)}`;
 
+        const edit = document.createElement("button");
+        edit.className = "tl-edit";
+        edit.textContent = "\u270E";
+        edit.title = "Edit";
+        edit.addEventListener("click", () => openEntryForm(w));
+
         const del = document.createElement("button");
         del.className = "tl-delete";
         del.textContent = "\u00d7";
         del.title = "Delete";
         del.addEventListener("click", async () => {
-          await invoke("cmd_delete_workout", { id: w.id });
+          try {
+            await invoke("cmd_delete_workout", { id: w.id });
+            showUndo(`${describeWorkout(w)} moved to trash`);
+          } catch (e) {
+            // Already gone (e.g. a double click): the reload below shows that
+            if (commandError(e).code !== "not-found") {
+              console.error("Failed to delete entry:", e);
+            }
+          }
           loadStats();
         });
 
@@ -143,6 +427,7 @@ async function loadStats() {
         entry.appendChild(dot);
         entry.appendChild(desc);
         entry.appendChild(sitting);
+        entry.appendChild(edit);
         entry.appendChild(del);
         timeline.appendChild(entry);
       }
@@ -152,16 +437,102 @@ async function loadStats() {
   }
 }
 
+async function loadTrash() {
+  try {
+    const trashed = await invoke<TrashedWorkout[]>("cmd_get_trash");
+    trashList.innerHTML = "";
+    trashEmpty.classList.toggle("hidden", trashed.length > 0);
+
+    for (const w of trashed) {
+      const entry = document.createElement("div");
+      entry.className = "tl-entry";
+
+      const time = document.createElement("span");
+      time.className = "tl-time";
+      time.textContent = wallClock(w.started_at, w.utc_offset_s).toLocaleDateString(undefined, {
+        month: "short",
+        day: "numeric",
+        timeZone: "UTC",
+      }) + " " + formatTime24(w.started_at, w.utc_offset_s);
+
+      const dot = document.createElement("span");
+      dot.className = `tl-dot ${w.workout_type}`;
+
+      const desc = document.createElement("span");
+      desc.className = "tl-desc";
+      desc.textContent = describeWorkout(w);
+
+      const restore = document.createElement("button");
+      restore.className = "tl-restore";
+      restore.textContent = "Restore";
+      restore.addEventListener("click", async () => {
+        try {
+          await invoke("cmd_restore_workout", { id: w.id });
+        } catch (e) {
+          // Purged from the trash meanwhile: the reload below drops it
+          if (commandError(e).code !== "not-found") {
+            console.error("Failed to restore entry:", e);
+          }
+        }
+        await loadTrash();
+        loadStats();
+      });
+
+      entry.appendChild(time);
+      entry.appendChild(dot);
+      entry.appendChild(desc);
+      entry.appendChild(restore);
+      trashList.appendChild(entry);
+    }
+  } catch (e) {
+    console.error("Failed to load trash:", e);
+  }
+}
+
+function setTrashVisible(visible: boolean) {
+  showingTrash = visible;
+  btnTrash.classList.toggle("active", visible);
+  trashEl.classList.toggle("hidden", !visible);
+  if (visible) {
+    closeEntryForm();
+    loadTrash();
+  }
+  loadStats();
+}
+
+btnTrash.addEventListener("click", () => setTrashVisible(!showingTrash));
+
+document.getElementById("btn-undo")!.addEventListener("click", undoLastChange);
+
+btnAddEntry.addEventListener("click", () => {
+  if (showingTrash) setTrashVisible(false);
+  openEntryForm(null);
+});
+
+entryType.addEventListener("change", () => {
+  if (editingId === null) {
+    entryDuration.value = String(defaultDurationMin[entryType.value] ?? 0);
+  }
+});
+
+entryForm.addEventListener("submit", (e) => {
+  e.preventDefault();
+  saveEntry();
+});
+
+document.getElementById("entry-cancel")!.addEventListener("click", closeEntryForm);
+
 btnPrev.addEventListener("click", () => {
+  closeEntryForm();
   currentDate.setDate(currentDate.getDate() - 1);
   loadStats();
 });
 
 btnNext.addEventListener("click", () => {
-  const today = formatDateISO(new Date());
   const next = new Date(currentDate);
   next.setDate(next.getDate() + 1);
-  if (formatDateISO(next) <= today) {
+  if (formatDateISO(next) <= formatDateISO(today())) {
+    closeEntryForm();
     currentDate = next;
     loadStats();
   }
@@ -174,11 +545,15 @@ getCurrentWindow().onCloseRequested(async (event) => {
 });
 
 // Reload when window gains focus
-getCurrentWindow().onFocusChanged(({ payload: focused }) => {
+getCurrentWindow().onFocusChanged(async ({ payload: focused }) => {
   if (focused) {
-    currentDate = new Date();
+    await loadDayStart();
+    currentDate = today();
     loadStats();
   }
 });
 
-loadStats();
+loadDayStart().then(() => {
+  currentDate = today();
+  loadStats();
+});